@@ -0,0 +1,81 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use hela::{
+    Account, CsvDataReader, CsvWriterStdout, InMemoryStore, ParallelCsvReader, PaymentEngine,
+    Transaction, Workload, WorkloadGenerator,
+};
+use std::io::Write;
+use tempfile::NamedTempFile;
+
+const ROWS: usize = 100_000;
+
+fn workload() -> Vec<Transaction> {
+    WorkloadGenerator::new(Workload {
+        rows: ROWS,
+        ..Default::default()
+    })
+    .collect()
+}
+
+fn reader(c: &mut Criterion) {
+    let mut data = vec![];
+    CsvWriterStdout::write_transactions(workload().into_iter(), Some(&mut data)).unwrap();
+    let mut group = c.benchmark_group("reader");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("csv", |b| {
+        b.iter(|| CsvDataReader::from_reader(black_box(&data[..])).count())
+    });
+    // an unknown column name forces the serde path
+    let mut serde_data = b"type,client,tx,amount,note".to_vec();
+    serde_data.extend(data.iter().skip_while(|&&byte| byte != b'\n'));
+    group.bench_function("csv_serde", |b| {
+        b.iter(|| CsvDataReader::from_reader(black_box(&serde_data[..])).count())
+    });
+    let mut input_file = NamedTempFile::new().unwrap();
+    input_file.write_all(&data).unwrap();
+    let fname = input_file.path().to_str().unwrap();
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    group.bench_function("csv_parallel", |b| {
+        b.iter(|| ParallelCsvReader::new(fname, threads).unwrap().count())
+    });
+    group.finish();
+}
+
+fn engine(c: &mut Criterion) {
+    let txns = workload();
+    let mut group = c.benchmark_group("engine");
+    group.throughput(Throughput::Elements(txns.len() as u64));
+    group.bench_function("execute_transactions", |b| {
+        b.iter_batched(
+            || PaymentEngine::new(Box::new(InMemoryStore::new())),
+            |mut engine| {
+                engine
+                    .execute_transactions(txns.iter().copied().map(Ok))
+                    .unwrap();
+                engine
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn writer(c: &mut Criterion) {
+    let mut engine = PaymentEngine::new(Box::new(InMemoryStore::new()));
+    engine
+        .execute_transactions(workload().into_iter().map(Ok))
+        .unwrap();
+    let accounts: Vec<Account> = engine.accounts().unwrap().collect();
+    let mut group = c.benchmark_group("writer");
+    group.throughput(Throughput::Elements(accounts.len() as u64));
+    group.bench_function("csv", |b| {
+        b.iter(|| {
+            let mut output = Vec::with_capacity(64 * accounts.len());
+            CsvWriterStdout::write(Box::new(accounts.iter().copied()), Some(&mut output)).unwrap();
+            output
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, reader, engine, writer);
+criterion_main!(benches);
@@ -0,0 +1,11 @@
+//! Arbitrary bytes must produce transactions or errors, never panics.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use hela::CsvDataReader;
+
+fuzz_target!(|data: &[u8]| {
+    for txn in CsvDataReader::from_reader(data) {
+        let _ = txn;
+    }
+});
@@ -0,0 +1,17 @@
+//! Arbitrary bytes run through the reader, the engine and the writer must never panic,
+//! and every account must keep its invariants (checked by the store in debug builds).
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use hela::{CsvDataReader, CsvWriterStdout, InMemoryStore, PaymentEngine};
+
+fuzz_target!(|data: &[u8]| {
+    let mut engine = PaymentEngine::new(Box::new(InMemoryStore::new()))
+        .with_dispute_window(1_000)
+        .with_chargeback_window(1_000);
+    // malformed rows end the run with an error, like they do in the CLI
+    let _ = engine.execute_transactions(CsvDataReader::from_reader(data));
+    let mut output = vec![];
+    let accounts = engine.accounts().unwrap();
+    CsvWriterStdout::write(accounts, Some(&mut output)).unwrap();
+});
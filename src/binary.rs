@@ -0,0 +1,317 @@
+//! Compact binary encoding of transactions for internal pipelines.
+//!
+//! A file starts with a header: the magic bytes `HELA`, the format version and the
+//! record size (little-endian `u16`s), followed by a CRC-32 of those bytes. Frames follow,
+//! each a little-endian `u32` payload length, a payload of at most 4096 fixed-width records
+//! and a CRC-32 of the payload.
+//!
+//! A record is 26 bytes: type (`u8`), presence flags (`u8`), client (`u16`), tx (`u32`),
+//! amount (`f64`), timestamp (`u64`) and destination (`u16`), all little-endian.
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+
+use crate::{Fallible, HelaError, Transaction, TransactionSource, TransactionType};
+
+/// Magic bytes starting a binary transactions file
+pub const BINARY_MAGIC: &[u8; 4] = b"HELA";
+/// Version of the binary format written
+pub const BINARY_VERSION: u16 = 1;
+
+const RECORD_SIZE: usize = 26;
+const HEADER_SIZE: usize = 12;
+/// Records per frame written, and at most read
+const FRAME_RECORDS: usize = 4096;
+
+const HAS_AMOUNT: u8 = 0b001;
+const HAS_TIMESTAMP: u8 = 0b010;
+const HAS_DESTINATION: u8 = 0b100;
+
+fn encode(txn: &Transaction, buf: &mut Vec<u8>) {
+    let r#type = TransactionType::ALL
+        .iter()
+        .position(|r#type| *r#type == txn.r#type);
+    let mut flags = 0;
+    for (present, flag) in [
+        (txn.amount.is_some(), HAS_AMOUNT),
+        (txn.timestamp.is_some(), HAS_TIMESTAMP),
+        (txn.destination.is_some(), HAS_DESTINATION),
+    ] {
+        if present {
+            flags |= flag;
+        }
+    }
+    buf.push(r#type.unwrap_or_default() as u8);
+    buf.push(flags);
+    buf.extend_from_slice(&txn.client.to_le_bytes());
+    buf.extend_from_slice(&txn.id.to_le_bytes());
+    buf.extend_from_slice(&txn.amount.unwrap_or_default().to_le_bytes());
+    buf.extend_from_slice(&txn.timestamp.unwrap_or_default().to_le_bytes());
+    buf.extend_from_slice(&txn.destination.unwrap_or_default().to_le_bytes());
+}
+
+fn decode(rec: &[u8]) -> Option<Transaction> {
+    let flags = rec[1];
+    let present = |flag| flags & flag != 0;
+    Some(Transaction {
+        r#type: *TransactionType::ALL.get(rec[0] as usize)?,
+        client: u16::from_le_bytes(rec[2..4].try_into().ok()?),
+        id: u32::from_le_bytes(rec[4..8].try_into().ok()?),
+        amount: Some(f64::from_le_bytes(rec[8..16].try_into().ok()?))
+            .filter(|_| present(HAS_AMOUNT)),
+        timestamp: Some(u64::from_le_bytes(rec[16..24].try_into().ok()?))
+            .filter(|_| present(HAS_TIMESTAMP)),
+        destination: Some(u16::from_le_bytes(rec[24..26].try_into().ok()?))
+            .filter(|_| present(HAS_DESTINATION)),
+        disputed: 0.0,
+        disputed_at: None,
+        charged_back: 0.0,
+        refunded: 0.0,
+    })
+}
+
+/// Binary Data Reader
+pub struct BinaryReader<R = BufReader<File>> {
+    rdr: R,
+    frame: Vec<u8>,
+    /// Offset of the next record within the frame
+    offset: usize,
+    /// Byte offset of the current frame within the file
+    position: u64,
+    /// Whether a frame could not be read, leaving the rest of the file unreadable
+    failed: bool,
+}
+
+impl BinaryReader {
+    /// Create new reader from a path
+    pub fn new(fname: &str) -> Fallible<BinaryReader> {
+        BinaryReader::from_reader(BufReader::new(File::open(fname)?))
+    }
+}
+
+impl<R: Read> BinaryReader<R> {
+    /// Create new reader over any source of binary data, checking its header
+    pub fn from_reader(mut rdr: R) -> Fallible<BinaryReader<R>> {
+        let mut header = [0; HEADER_SIZE];
+        rdr.read_exact(&mut header)
+            .map_err(|_| invalid("truncated header"))?;
+        if &header[..4] != BINARY_MAGIC {
+            return Err(invalid("not a hela binary file"));
+        }
+        if crc32fast::hash(&header[..8]).to_le_bytes() != header[8..] {
+            return Err(invalid("header checksum mismatch"));
+        }
+        let version = u16::from_le_bytes([header[4], header[5]]);
+        if version != BINARY_VERSION {
+            return Err(invalid(&format!("unsupported version {}", version)));
+        }
+        let record_size = u16::from_le_bytes([header[6], header[7]]) as usize;
+        if record_size != RECORD_SIZE {
+            return Err(invalid(&format!("unsupported record size {}", record_size)));
+        }
+        Ok(BinaryReader {
+            rdr,
+            frame: vec![],
+            offset: 0,
+            position: HEADER_SIZE as u64,
+            failed: false,
+        })
+    }
+
+    /// Read the next frame, returning false at the end of the file
+    fn read_frame(&mut self) -> Fallible<bool> {
+        if self.failed {
+            return Ok(false);
+        }
+        let mut len = [0; 4];
+        let mut read = 0;
+        while read < len.len() {
+            match self.rdr.read(&mut len[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(self.unreadable_frame("truncated")),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let len = u32::from_le_bytes(len) as usize;
+        // checked before allocating the frame, as a corrupt length could be up to 4 GiB
+        if len > FRAME_RECORDS * RECORD_SIZE {
+            return Err(self.unreadable_frame("too long"));
+        }
+        // is_multiple_of would need Rust 1.87
+        #[allow(clippy::manual_is_multiple_of)]
+        if len % RECORD_SIZE != 0 {
+            return Err(self.unreadable_frame("length is not a whole number of records"));
+        }
+        let mut checksum = [0; 4];
+        self.offset = 0;
+        self.frame.resize(len, 0);
+        self.rdr
+            .read_exact(&mut self.frame)
+            .and_then(|_| self.rdr.read_exact(&mut checksum))
+            .map_err(|_| self.unreadable_frame("truncated"))?;
+        let checked = crc32fast::hash(&self.frame).to_le_bytes() == checksum;
+        if !checked {
+            // skip the whole frame, the next one can still be read
+            let error = self.invalid_frame("checksum mismatch");
+            self.frame.clear();
+            self.position += 8 + len as u64;
+            return Err(error);
+        }
+        self.position += 8 + len as u64;
+        Ok(true)
+    }
+
+    fn invalid_frame(&self, what: &str) -> HelaError {
+        invalid(&format!("frame at byte {}: {}", self.position, what))
+    }
+
+    /// Error of a frame that cannot be skipped, ending the file
+    fn unreadable_frame(&mut self, what: &str) -> HelaError {
+        self.failed = true;
+        self.frame.clear();
+        self.invalid_frame(what)
+    }
+
+    fn read(&mut self) -> Fallible<Option<Transaction>> {
+        while self.offset >= self.frame.len() {
+            if !self.read_frame()? {
+                return Ok(None);
+            }
+        }
+        let rec = &self.frame[self.offset..self.offset + RECORD_SIZE];
+        self.offset += RECORD_SIZE;
+        decode(rec)
+            .map(Some)
+            .ok_or_else(|| invalid(&format!("unknown transaction type {}", rec[0])))
+    }
+}
+
+fn invalid(what: &str) -> HelaError {
+    HelaError::BinaryError(what.to_string())
+}
+
+impl<R: Read> Iterator for BinaryReader<R> {
+    type Item = Fallible<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+impl<R: Read> TransactionSource for BinaryReader<R> {}
+
+/// Binary Data Writer
+pub struct BinaryWriter<W: Write> {
+    wtr: W,
+    frame: Vec<u8>,
+}
+
+impl BinaryWriter<BufWriter<File>> {
+    /// Create new writer to a path
+    pub fn create(fname: &str) -> Fallible<BinaryWriter<BufWriter<File>>> {
+        BinaryWriter::new(BufWriter::new(File::create(fname)?))
+    }
+}
+
+impl<W: Write> BinaryWriter<W> {
+    /// Create new writer, writing the header
+    pub fn new(mut wtr: W) -> Fallible<BinaryWriter<W>> {
+        let mut header = BINARY_MAGIC.to_vec();
+        header.extend_from_slice(&BINARY_VERSION.to_le_bytes());
+        header.extend_from_slice(&(RECORD_SIZE as u16).to_le_bytes());
+        let checksum = crc32fast::hash(&header);
+        header.extend_from_slice(&checksum.to_le_bytes());
+        wtr.write_all(&header)?;
+        Ok(BinaryWriter {
+            wtr,
+            frame: Vec::with_capacity(FRAME_RECORDS * RECORD_SIZE),
+        })
+    }
+
+    /// Write a transaction
+    pub fn write(&mut self, txn: &Transaction) -> Fallible<()> {
+        encode(txn, &mut self.frame);
+        if self.frame.len() >= FRAME_RECORDS * RECORD_SIZE {
+            self.write_frame()?;
+        }
+        Ok(())
+    }
+
+    fn write_frame(&mut self) -> Fallible<()> {
+        if !self.frame.is_empty() {
+            self.wtr
+                .write_all(&(self.frame.len() as u32).to_le_bytes())?;
+            self.wtr.write_all(&self.frame)?;
+            self.wtr
+                .write_all(&crc32fast::hash(&self.frame).to_le_bytes())?;
+            self.frame.clear();
+        }
+        Ok(())
+    }
+
+    /// Write the last frame and flush, returning the underlying writer
+    pub fn finish(mut self) -> Fallible<W> {
+        self.write_frame()?;
+        self.wtr.flush()?;
+        Ok(self.wtr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Workload, WorkloadGenerator};
+
+    fn write(txns: &[Transaction]) -> Vec<u8> {
+        let mut wtr = BinaryWriter::new(vec![]).unwrap();
+        for txn in txns {
+            wtr.write(txn).unwrap();
+        }
+        wtr.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trip() -> Fallible<()> {
+        let mut txns: Vec<_> = WorkloadGenerator::new(Workload {
+            rows: 10_000,
+            ..Default::default()
+        })
+        .collect();
+        txns[0].r#type = TransactionType::Transfer;
+        txns[0].timestamp = Some(1_614_556_800);
+        txns[0].destination = Some(7);
+        let data = write(&txns);
+        assert_eq!(data.len(), 12 + 3 * 8 + 10_000 * 26);
+        let read = BinaryReader::from_reader(&data[..])?.collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(read, txns);
+        Ok(())
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let txns: Vec<_> = WorkloadGenerator::new(Workload {
+            rows: 10,
+            ..Default::default()
+        })
+        .collect();
+        let mut data = write(&txns);
+        data[30] ^= 1;
+        let read: Vec<_> = BinaryReader::from_reader(&data[..]).unwrap().collect();
+        assert!(matches!(&read[..], [Err(HelaError::BinaryError(_))]));
+
+        let mut long = data[..HEADER_SIZE].to_vec();
+        long.extend_from_slice(&(u32::MAX - 21).to_le_bytes());
+        long.extend_from_slice(&[0; 26]);
+        let read: Vec<_> = BinaryReader::from_reader(&long[..]).unwrap().collect();
+        assert!(
+            matches!(&read[..], [Err(HelaError::BinaryError(e))] if e.ends_with("too long")),
+            "{:?}",
+            read
+        );
+
+        data[4] = 2;
+        assert!(BinaryReader::from_reader(&data[..]).is_err());
+    }
+}
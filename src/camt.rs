@@ -0,0 +1,588 @@
+//! Import of ISO 20022 camt.053 bank to customer statements.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use quick_xml::escape::resolve_predefined_entity;
+use quick_xml::events::Event;
+
+use crate::{
+    Amount, Client, Fallible, HelaError, Timestamp, Transaction, TransactionId, TransactionSource,
+    TransactionType,
+};
+
+/// Fields of a statement entry (`Ntry`), collected as it is read
+#[derive(Default)]
+struct Entry {
+    amount: Option<String>,
+    currency: Option<String>,
+    credit: Option<bool>,
+    reversal: bool,
+    status: Option<String>,
+    booked: Option<String>,
+    entry_ref: Option<String>,
+    servicer_ref: Option<String>,
+    end_to_end: Option<String>,
+}
+
+impl Entry {
+    /// Reference identifying the entry, and the entry it reverses
+    fn reference(&self) -> Option<&str> {
+        self.end_to_end
+            .as_deref()
+            .filter(|id| *id != "NOTPROVIDED")
+            .or(self.servicer_ref.as_deref())
+            .or(self.entry_ref.as_deref())
+    }
+}
+
+/// Transaction ids given to statement entries, and the references of the entries they were
+/// given to. Shared by the statements of a run so that their entries get distinct ids, and a
+/// reversal finds the entry it reverses in an earlier statement.
+#[derive(Clone, Debug)]
+pub struct StatementIds(Arc<Mutex<Sequence>>);
+
+#[derive(Debug)]
+struct Sequence {
+    next: TransactionId,
+    references: HashMap<String, TransactionId>,
+}
+
+impl StatementIds {
+    /// Sequence starting at `first`
+    pub fn starting_at(first: TransactionId) -> StatementIds {
+        StatementIds(Arc::new(Mutex::new(Sequence {
+            next: first,
+            references: HashMap::new(),
+        })))
+    }
+
+    /// Transaction id given to the entry with a reference, if any was read so far
+    pub fn reference(&self, reference: &str) -> Option<TransactionId> {
+        self.sequence().references.get(reference).copied()
+    }
+
+    /// Next id, given to the entry with `reference` unless it is empty
+    fn next(&self, reference: Option<&str>) -> TransactionId {
+        let mut sequence = self.sequence();
+        let id = sequence.next;
+        sequence.next = id.wrapping_add(1);
+        if let Some(reference) = reference.filter(|reference| !reference.is_empty()) {
+            sequence.references.insert(reference.to_string(), id);
+        }
+        id
+    }
+
+    fn sequence(&self) -> MutexGuard<'_, Sequence> {
+        // the sequence is left consistent by every update, even one that panicked
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Default for StatementIds {
+    fn default() -> Self {
+        Self::starting_at(1)
+    }
+}
+
+/// Sequences are equal if they are shared
+impl PartialEq for StatementIds {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+/// camt.053 Statement Reader
+///
+/// Booked entries become transactions of the client mapped to the statement's account:
+/// credits are deposits and debits are withdrawals. A reversed credit refunds the deposit
+/// with the same reference, read from any statement sharing the [`StatementIds`], and a
+/// reversed debit is deposited back. Entries must be in the currency of the account, or else
+/// of the first booked entry of their statement. Entries are numbered
+/// from 1 in statement order, or from a [`StatementIds`] sequence shared with other statements.
+/// The ids do not take other input files into account, so when mixing statements with other
+/// transactions the sequence has to start above their ids.
+pub struct Camt053Reader<R = BufReader<File>> {
+    xml: quick_xml::Reader<R>,
+    buf: Vec<u8>,
+    /// Names of the open elements
+    path: Vec<Vec<u8>>,
+    text: String,
+    account: Option<String>,
+    /// Currency of the statement's account, or else of its first booked entry
+    currency: Option<String>,
+    entry: Option<Entry>,
+    accounts: HashMap<String, Client>,
+    ids: StatementIds,
+}
+
+impl Camt053Reader {
+    /// Create new reader from a path, with the client of each account (IBAN or other identifier)
+    pub fn new(fname: &str, accounts: HashMap<String, Client>) -> Fallible<Camt053Reader> {
+        Ok(Camt053Reader::from_reader(
+            BufReader::new(File::open(fname)?),
+            accounts,
+        ))
+    }
+}
+
+impl<R: BufRead> Camt053Reader<R> {
+    /// Create new reader over any source of camt.053 data
+    pub fn from_reader(rdr: R, accounts: HashMap<String, Client>) -> Camt053Reader<R> {
+        Camt053Reader {
+            xml: quick_xml::Reader::from_reader(rdr),
+            buf: vec![],
+            path: vec![],
+            text: String::new(),
+            account: None,
+            currency: None,
+            entry: None,
+            accounts,
+            ids: StatementIds::default(),
+        }
+    }
+
+    /// Take the ids of entries from a sequence, which may be shared with other statements
+    pub fn with_ids(mut self, ids: StatementIds) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    fn in_path(&self, names: &[&[u8]]) -> bool {
+        self.path.len() >= names.len()
+            && self.path[self.path.len() - names.len()..]
+                .iter()
+                .zip(names)
+                .all(|(name, expected)| name == expected)
+    }
+
+    fn read(&mut self) -> Fallible<Option<Transaction>> {
+        loop {
+            self.buf.clear();
+            match self
+                .xml
+                .read_event_into(&mut self.buf)
+                .map_err(HelaError::XmlError)?
+            {
+                Event::Start(e) => {
+                    self.path.push(e.local_name().as_ref().to_vec());
+                    let currency = match e.local_name().as_ref() {
+                        b"Amt" => e
+                            .try_get_attribute("Ccy")
+                            .map_err(|e| HelaError::XmlError(e.into()))?
+                            .map(|ccy| ccy.unescape_value().map(String::from))
+                            .transpose()
+                            .map_err(HelaError::XmlError)?,
+                        _ => None,
+                    };
+                    self.text.clear();
+                    if self.in_path(&[b"Stmt"]) {
+                        self.currency = None;
+                    } else if self.in_path(&[b"Stmt", b"Ntry"]) {
+                        self.entry = Some(Entry::default());
+                    } else if self.in_path(&[b"Ntry", b"Amt"]) {
+                        if let Some(entry) = self.entry.as_mut() {
+                            entry.currency = currency;
+                        }
+                    }
+                }
+                Event::Text(e) => self
+                    .text
+                    .push_str(&e.decode().map_err(|e| HelaError::XmlError(e.into()))?),
+                Event::CData(e) => self
+                    .text
+                    .push_str(&e.decode().map_err(|e| HelaError::XmlError(e.into()))?),
+                Event::GeneralRef(e) => {
+                    if let Some(ch) = e.resolve_char_ref().map_err(HelaError::XmlError)? {
+                        self.text.push(ch);
+                    } else {
+                        let name = e.decode().map_err(|e| HelaError::XmlError(e.into()))?;
+                        self.text
+                            .push_str(resolve_predefined_entity(&name).unwrap_or_default());
+                    }
+                }
+                Event::End(_) => {
+                    if let Some(txn) = self.end_element()? {
+                        return Ok(Some(txn));
+                    }
+                }
+                Event::Eof => return Ok(None),
+                _ => {}
+            }
+        }
+    }
+
+    /// Record the value of the element being closed, returning the transaction of a closed entry
+    fn end_element(&mut self) -> Fallible<Option<Transaction>> {
+        let value = Some(self.text.trim().to_string()).filter(|value| !value.is_empty());
+        self.text.clear();
+        if self.in_path(&[b"Stmt", b"Acct", b"Id", b"IBAN"])
+            || self.in_path(&[b"Stmt", b"Acct", b"Id", b"Othr", b"Id"])
+        {
+            self.account = value.clone();
+        }
+        if self.in_path(&[b"Stmt", b"Acct", b"Ccy"]) {
+            self.currency = value.clone();
+        }
+        let in_entry = |names: &[&[u8]]| {
+            let mut path = vec![&b"Ntry"[..]];
+            path.extend_from_slice(names);
+            self.in_path(&path)
+        };
+        let (amount, credit, reversal, status, cd, date, date_time, entry_ref, servicer_ref) = (
+            in_entry(&[b"Amt"]),
+            in_entry(&[b"CdtDbtInd"]),
+            in_entry(&[b"RvslInd"]),
+            in_entry(&[b"Sts"]),
+            in_entry(&[b"Sts", b"Cd"]),
+            in_entry(&[b"BookgDt", b"Dt"]),
+            in_entry(&[b"BookgDt", b"DtTm"]),
+            in_entry(&[b"NtryRef"]),
+            in_entry(&[b"AcctSvcrRef"]),
+        );
+        let end_to_end = self.in_path(&[b"TxDtls", b"Refs", b"EndToEndId"]);
+        let closed_entry = self.in_path(&[b"Stmt", b"Ntry"]);
+        self.path.pop();
+
+        let entry = match self.entry.as_mut() {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        if amount {
+            entry.amount = value;
+        } else if credit {
+            entry.credit = value.map(|value| value == "CRDT");
+        } else if reversal {
+            entry.reversal = value.as_deref() == Some("true");
+        } else if status && value.is_some() || cd {
+            entry.status = value;
+        } else if date || date_time {
+            entry.booked = value;
+        } else if entry_ref {
+            entry.entry_ref = value;
+        } else if servicer_ref {
+            entry.servicer_ref = value;
+        } else if end_to_end && entry.end_to_end.is_none() {
+            entry.end_to_end = value;
+        } else if closed_entry {
+            let entry = self.entry.take().unwrap_or_default();
+            return self.transaction(entry);
+        }
+        Ok(None)
+    }
+
+    /// Map a closed entry to a transaction, skipping entries that are not booked
+    fn transaction(&mut self, entry: Entry) -> Fallible<Option<Transaction>> {
+        if entry
+            .status
+            .as_deref()
+            .is_some_and(|status| status != "BOOK")
+        {
+            return Ok(None);
+        }
+        let reference = entry.reference().unwrap_or_default().to_string();
+        let invalid =
+            |what: &str| HelaError::StatementError(format!("{} of entry '{}'", what, reference));
+        match (&self.currency, entry.currency) {
+            (Some(expected), Some(currency)) if *expected != currency => {
+                return Err(invalid(&format!(
+                    "currency {} differing from the statement's {}",
+                    currency, expected
+                )))
+            }
+            (None, currency) => self.currency = currency,
+            _ => {}
+        }
+        let client = self
+            .account
+            .as_ref()
+            .and_then(|account| self.accounts.get(account))
+            .copied()
+            .ok_or_else(|| {
+                HelaError::StatementError(format!(
+                    "no client for statement account '{}'",
+                    self.account.as_deref().unwrap_or_default()
+                ))
+            })?;
+        let amount = entry
+            .amount
+            .as_deref()
+            .and_then(|amount| amount.parse::<Amount>().ok())
+            .ok_or_else(|| invalid("invalid amount"))?;
+        let credit = entry
+            .credit
+            .ok_or_else(|| invalid("missing credit or debit indicator"))?;
+        let timestamp = match entry.booked.as_deref() {
+            Some(booked) => {
+                Some(parse_timestamp(booked).ok_or_else(|| invalid("invalid booking date"))?)
+            }
+            None => None,
+        };
+
+        let (r#type, id) = match (entry.reversal, credit) {
+            (true, false) => match self.ids.reference(&reference) {
+                Some(id) if !reference.is_empty() => (TransactionType::Refund, id),
+                // refund of an unknown deposit, which the engine rejects
+                _ => (TransactionType::Refund, self.ids.next(None)),
+            },
+            (true, true) => (TransactionType::Deposit, self.ids.next(None)),
+            (false, credit) => {
+                let id = self.ids.next(Some(&reference));
+                let r#type = if credit {
+                    TransactionType::Deposit
+                } else {
+                    TransactionType::Withdrawal
+                };
+                (r#type, id)
+            }
+        };
+        Ok(Some(Transaction {
+            r#type,
+            client,
+            id,
+            amount: Some(amount),
+            timestamp,
+            destination: None,
+            disputed: 0.0,
+            disputed_at: None,
+            charged_back: 0.0,
+            refunded: 0.0,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Camt053Reader<R> {
+    type Item = Fallible<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+impl<R: BufRead> TransactionSource for Camt053Reader<R> {}
+
+/// Parse an ISO 8601 date (`2021-03-01`) or date time (`2021-03-01T10:00:00+01:00`)
+fn parse_timestamp(value: &str) -> Option<Timestamp> {
+    let (date, time) = match value.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (value, None),
+    };
+    let mut parts = date.splitn(3, '-');
+    let year: i64 = parts.next()?.parse().ok()?;
+    let month: i64 = parts.next()?.parse().ok()?;
+    let day: i64 = parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut seconds = days_from_civil(year, month, day) * 86_400;
+    if let Some(time) = time {
+        let (clock, offset) = match time.strip_suffix('Z') {
+            Some(clock) => (clock, 0),
+            None => match time.rfind(['+', '-']) {
+                Some(idx) => {
+                    let sign = if time[idx..].starts_with('-') { -1 } else { 1 };
+                    (&time[..idx], sign * parse_clock(&time[idx + 1..])?)
+                }
+                None => (time, 0),
+            },
+        };
+        seconds += parse_clock(clock)? - offset;
+    }
+    Timestamp::try_from(seconds).ok()
+}
+
+/// Seconds since midnight of `hh:mm[:ss[.fff]]`
+fn parse_clock(clock: &str) -> Option<i64> {
+    let mut parts = clock.splitn(3, ':');
+    let hours: i64 = parts.next()?.parse().ok()?;
+    let minutes: i64 = parts.next()?.parse().ok()?;
+    let seconds: i64 = match parts.next() {
+        Some(seconds) => seconds.split('.').next()?.parse().ok()?,
+        None => 0,
+    };
+    Some(hours * 3_600 + minutes * 60 + seconds)
+}
+
+/// Days since the Unix epoch of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const STATEMENT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.02">
+  <BkToCstmrStmt>
+    <Stmt>
+      <Id>STMT-1</Id>
+      <Acct><Id><IBAN>DE89370400440532013000</IBAN></Id></Acct>
+      <Ntry>
+        <NtryRef>E1</NtryRef>
+        <Amt Ccy="EUR">100.50</Amt>
+        <CdtDbtInd>CRDT</CdtDbtInd>
+        <Sts>BOOK</Sts>
+        <BookgDt><Dt>2021-03-01</Dt></BookgDt>
+        <NtryDtls><TxDtls><Refs><EndToEndId>INV&amp;1</EndToEndId></Refs></TxDtls></NtryDtls>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">20</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <Sts>BOOK</Sts>
+        <BookgDt><DtTm>2021-03-01T10:00:00+01:00</DtTm></BookgDt>
+        <AcctSvcrRef>S2</AcctSvcrRef>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">5</Amt>
+        <CdtDbtInd>CRDT</CdtDbtInd>
+        <Sts>PDNG</Sts>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">30.25</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <RvslInd>true</RvslInd>
+        <Sts><Cd>BOOK</Cd></Sts>
+        <BookgDt><Dt>2021-03-02</Dt></BookgDt>
+        <NtryDtls><TxDtls><Refs><EndToEndId>INV&amp;1</EndToEndId></Refs></TxDtls></NtryDtls>
+      </Ntry>
+    </Stmt>
+  </BkToCstmrStmt>
+</Document>"#;
+
+    fn read(accounts: &[(&str, Client)]) -> Vec<Fallible<Transaction>> {
+        let accounts = accounts
+            .iter()
+            .map(|(account, client)| (account.to_string(), *client))
+            .collect();
+        Camt053Reader::from_reader(STATEMENT.as_bytes(), accounts).collect()
+    }
+
+    #[test]
+    fn maps_entries() {
+        let txns: Vec<_> = read(&[("DE89370400440532013000", 7)])
+            .into_iter()
+            .map(|txn| txn.unwrap())
+            .collect();
+        let summary: Vec<_> = txns
+            .iter()
+            .map(|txn| {
+                (
+                    txn.r#type,
+                    txn.client,
+                    txn.id,
+                    txn.amount.unwrap(),
+                    txn.timestamp,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (TransactionType::Deposit, 7, 1, 100.5, Some(1_614_556_800)),
+                (TransactionType::Withdrawal, 7, 2, 20.0, Some(1_614_589_200)),
+                (TransactionType::Refund, 7, 1, 30.25, Some(1_614_643_200)),
+            ]
+        );
+    }
+
+    #[test]
+    fn merges_statements() {
+        let accounts: HashMap<_, _> = vec![("DE89370400440532013000".to_string(), 7)]
+            .into_iter()
+            .collect();
+        let ids = StatementIds::starting_at(10);
+        let sources = (1..=2)
+            .map(|day| {
+                let statement = STATEMENT.replace("INV&amp;1", &format!("INV&amp;{}", day));
+                let source: Box<dyn TransactionSource> = Box::new(
+                    Camt053Reader::from_reader(Cursor::new(statement), accounts.clone())
+                        .with_ids(ids.clone()),
+                );
+                (format!("day{}.xml", day), source)
+            })
+            .collect();
+        let txns: Vec<_> = crate::MergedSource::new(sources, crate::MergeOrder::Timestamp)
+            .map(|txn| {
+                let txn = txn.unwrap();
+                (txn.r#type, txn.id)
+            })
+            .collect();
+        assert_eq!(
+            txns,
+            vec![
+                (TransactionType::Deposit, 10),
+                (TransactionType::Deposit, 11),
+                (TransactionType::Withdrawal, 12),
+                (TransactionType::Withdrawal, 13),
+                (TransactionType::Refund, 10),
+                (TransactionType::Refund, 11),
+            ]
+        );
+    }
+
+    #[test]
+    fn reverses_entries_of_earlier_statements() {
+        let accounts: HashMap<_, _> = vec![("DE89370400440532013000".to_string(), 7)]
+            .into_iter()
+            .collect();
+        // the first statement books the entries, the second reverses the credit
+        let first_entry = STATEMENT.find("      <Ntry>").unwrap();
+        let reversal = STATEMENT.rfind("      <Ntry>").unwrap();
+        let end = STATEMENT.find("    </Stmt>").unwrap();
+        let booked = format!("{}{}", &STATEMENT[..reversal], &STATEMENT[end..]);
+        let reversed = format!("{}{}", &STATEMENT[..first_entry], &STATEMENT[reversal..]);
+
+        let ids = StatementIds::default();
+        let read = |statement: &str| -> Vec<_> {
+            Camt053Reader::from_reader(statement.as_bytes(), accounts.clone())
+                .with_ids(ids.clone())
+                .map(|txn| {
+                    let txn = txn.unwrap();
+                    (txn.r#type, txn.id)
+                })
+                .collect()
+        };
+        assert_eq!(
+            read(&booked),
+            vec![
+                (TransactionType::Deposit, 1),
+                (TransactionType::Withdrawal, 2)
+            ]
+        );
+        assert_eq!(read(&reversed), vec![(TransactionType::Refund, 1)]);
+        assert_eq!(ids.reference("INV&1"), Some(1));
+    }
+
+    #[test]
+    fn rejects_other_currencies() {
+        let accounts: HashMap<_, _> = vec![("DE89370400440532013000".to_string(), 7)]
+            .into_iter()
+            .collect();
+        let read = |statement: String| -> Vec<_> {
+            Camt053Reader::from_reader(Cursor::new(statement), accounts.clone()).collect()
+        };
+        // the first booked entry sets the currency of a statement without an account currency
+        let txns = read(STATEMENT.replace(r#"<Amt Ccy="EUR">20<"#, r#"<Amt Ccy="USD">20<"#));
+        assert!(txns[0].is_ok());
+        assert!(matches!(&txns[1], Err(HelaError::StatementError(e)) if e.contains("USD")));
+        assert!(txns[2].is_ok());
+
+        let txns = read(STATEMENT.replace("</Id></Acct>", "</Id><Ccy>USD</Ccy></Acct>"));
+        assert!(txns.iter().all(|txn| txn.is_err()));
+    }
+
+    #[test]
+    fn unmapped_account() {
+        let txns = read(&[]);
+        assert!(matches!(txns[0], Err(HelaError::StatementError(_))));
+    }
+}
@@ -0,0 +1,311 @@
+//! Parquet input of transactions and output of accounts and rejections.
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Float64Type, UInt16Type, UInt32Type, UInt64Type};
+use arrow_array::{
+    Array, ArrayRef, BooleanArray, Float64Array, RecordBatch, StringArray, UInt16Array, UInt32Array,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::ArrowWriter;
+
+use crate::data::parse_type;
+use crate::{Account, Fallible, HelaError, Rejection, Transaction, TransactionSource};
+
+/// Rows per record batch read or written
+const BATCH_SIZE: usize = 8192;
+
+/// Columns of a record batch, cast to the types of the transaction fields
+struct Columns {
+    r#type: StringArray,
+    client: ArrayRef,
+    tx: ArrayRef,
+    amount: Option<ArrayRef>,
+    timestamp: Option<ArrayRef>,
+    destination: Option<ArrayRef>,
+}
+
+impl Columns {
+    fn from_batch(batch: &RecordBatch) -> Result<Columns, ArrowError> {
+        let column = |name: &str, data_type: &DataType| -> Result<Option<ArrayRef>, ArrowError> {
+            batch
+                .column_by_name(name)
+                .map(|column| arrow_cast::cast(column, data_type))
+                .transpose()
+        };
+        let required = |name: &str, data_type: &DataType| {
+            column(name, data_type)?
+                .ok_or_else(|| ArrowError::SchemaError(format!("missing column '{}'", name)))
+        };
+        Ok(Columns {
+            r#type: required("type", &DataType::Utf8)?
+                .as_string::<i32>()
+                .clone(),
+            client: required("client", &DataType::UInt16)?,
+            tx: required("tx", &DataType::UInt32)?,
+            amount: column("amount", &DataType::Float64)?,
+            timestamp: column("timestamp", &DataType::UInt64)?,
+            destination: column("destination", &DataType::UInt16)?,
+        })
+    }
+
+    fn transaction(&self, row: usize) -> Result<Transaction, ArrowError> {
+        let invalid = |name: &str| {
+            ArrowError::InvalidArgumentError(format!(
+                "missing or invalid '{}' in row {}",
+                name, row
+            ))
+        };
+        let r#type = Some(&self.r#type)
+            .filter(|column| column.is_valid(row))
+            .and_then(|column| parse_type(column.value(row).trim().as_bytes()))
+            .ok_or_else(|| invalid("type"))?;
+        let client = Some(self.client.as_primitive::<UInt16Type>())
+            .filter(|column| column.is_valid(row))
+            .ok_or_else(|| invalid("client"))?
+            .value(row);
+        let id = Some(self.tx.as_primitive::<UInt32Type>())
+            .filter(|column| column.is_valid(row))
+            .ok_or_else(|| invalid("tx"))?
+            .value(row);
+        Ok(Transaction {
+            r#type,
+            client,
+            id,
+            amount: self.amount.as_ref().and_then(|column| {
+                let column = column.as_primitive::<Float64Type>();
+                Some(column.value(row)).filter(|_| column.is_valid(row))
+            }),
+            timestamp: self.timestamp.as_ref().and_then(|column| {
+                let column = column.as_primitive::<UInt64Type>();
+                Some(column.value(row)).filter(|_| column.is_valid(row))
+            }),
+            destination: self.destination.as_ref().and_then(|column| {
+                let column = column.as_primitive::<UInt16Type>();
+                Some(column.value(row)).filter(|_| column.is_valid(row))
+            }),
+            disputed: 0.0,
+            disputed_at: None,
+            charged_back: 0.0,
+            refunded: 0.0,
+        })
+    }
+}
+
+/// Parquet Data Reader
+///
+/// Reads columns named like the CSV columns, cast to the transaction field types,
+/// e.g. `client` may be stored as any integer type.
+pub struct ParquetReader {
+    batches: ParquetRecordBatchReader,
+    batch: Option<(Columns, usize)>,
+    row: usize,
+}
+
+impl ParquetReader {
+    /// Create new reader from a path
+    pub fn new(fname: &str) -> Fallible<ParquetReader> {
+        let batches = ParquetRecordBatchReaderBuilder::try_new(File::open(fname)?)
+            .and_then(|builder| builder.with_batch_size(BATCH_SIZE).build())
+            .map_err(HelaError::ParquetError)?;
+        Ok(ParquetReader {
+            batches,
+            batch: None,
+            row: 0,
+        })
+    }
+
+    fn read(&mut self) -> Fallible<Option<Transaction>> {
+        loop {
+            if let Some((columns, len)) = &self.batch {
+                if self.row < *len {
+                    self.row += 1;
+                    return columns
+                        .transaction(self.row - 1)
+                        .map(Some)
+                        .map_err(HelaError::ArrowError);
+                }
+            }
+            let batch = match self.batches.next() {
+                Some(batch) => batch.map_err(HelaError::ArrowError)?,
+                None => return Ok(None),
+            };
+            let columns = Columns::from_batch(&batch).map_err(HelaError::ArrowError)?;
+            self.batch = Some((columns, batch.num_rows()));
+            self.row = 0;
+        }
+    }
+}
+
+impl Iterator for ParquetReader {
+    type Item = Fallible<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+impl TransactionSource for ParquetReader {}
+
+/// Parquet Data Writer
+pub struct ParquetWriter;
+
+impl ParquetWriter {
+    /// Write accounts, optionally with their credit limits. Amounts are written unrounded.
+    pub fn write_accounts<W: Write + Send>(
+        accounts: impl Iterator<Item = Account>,
+        with_credit_limit: bool,
+        wtr: W,
+    ) -> Fallible<()> {
+        let mut fields = vec![
+            Field::new("client", DataType::UInt16, false),
+            Field::new("available", DataType::Float64, false),
+            Field::new("held", DataType::Float64, false),
+            Field::new("total", DataType::Float64, false),
+            Field::new("locked", DataType::Boolean, false),
+        ];
+        if with_credit_limit {
+            fields.push(Field::new("credit_limit", DataType::Float64, false));
+        }
+        Self::write_batches(Schema::new(fields), accounts, wtr, |accounts| {
+            let amounts = |amount: fn(&Account) -> f64| -> ArrayRef {
+                Arc::new(accounts.iter().map(amount).collect::<Float64Array>())
+            };
+            let mut columns: Vec<ArrayRef> = vec![
+                Arc::new(
+                    accounts
+                        .iter()
+                        .map(|acc| acc.client)
+                        .collect::<UInt16Array>(),
+                ),
+                amounts(|acc| acc.available),
+                amounts(|acc| acc.held),
+                amounts(|acc| acc.total),
+                Arc::new(
+                    accounts
+                        .iter()
+                        .map(|acc| Some(acc.locked))
+                        .collect::<BooleanArray>(),
+                ),
+            ];
+            if with_credit_limit {
+                columns.push(amounts(|acc| acc.credit_limit));
+            }
+            columns
+        })
+    }
+
+    /// Write a rejection report
+    pub fn write_rejections<'a, W: Write + Send>(
+        rejections: impl Iterator<Item = &'a Rejection>,
+        wtr: W,
+    ) -> Fallible<()> {
+        let schema = Schema::new(vec![
+            Field::new("client", DataType::UInt16, false),
+            Field::new("tx", DataType::UInt32, false),
+            Field::new("reason", DataType::Utf8, false),
+        ]);
+        Self::write_batches(schema, rejections, wtr, |rejections| {
+            vec![
+                Arc::new(
+                    rejections
+                        .iter()
+                        .map(|rej| rej.client)
+                        .collect::<UInt16Array>(),
+                ),
+                Arc::new(rejections.iter().map(|rej| rej.tx).collect::<UInt32Array>()),
+                Arc::new(
+                    rejections
+                        .iter()
+                        .map(|rej| Some(rej.reason.as_str()))
+                        .collect::<StringArray>(),
+                ),
+            ]
+        })
+    }
+
+    fn write_batches<T, W: Write + Send>(
+        schema: Schema,
+        records: impl Iterator<Item = T>,
+        wtr: W,
+        columns: impl Fn(&[T]) -> Vec<ArrayRef>,
+    ) -> Fallible<()> {
+        let schema = Arc::new(schema);
+        let mut writer =
+            ArrowWriter::try_new(wtr, schema.clone(), None).map_err(HelaError::ParquetError)?;
+        let mut records = records.peekable();
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        while records.peek().is_some() {
+            batch.clear();
+            batch.extend(records.by_ref().take(BATCH_SIZE));
+            let batch = RecordBatch::try_new(schema.clone(), columns(&batch))
+                .map_err(HelaError::ArrowError)?;
+            writer.write(&batch).map_err(HelaError::ParquetError)?;
+        }
+        writer.close().map_err(HelaError::ParquetError)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemoryStore, PaymentEngine, TransactionType};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn round_trip() -> Fallible<()> {
+        // a transactions file written with arrow directly, with the client as a wider integer
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("type", DataType::Utf8, false),
+            Field::new("client", DataType::Int64, false),
+            Field::new("tx", DataType::UInt32, false),
+            Field::new("amount", DataType::Float64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec!["deposit", "withdrawal", "dispute"])),
+                Arc::new(arrow_array::Int64Array::from(vec![1, 1, 1])),
+                Arc::new(UInt32Array::from(vec![1, 2, 1])),
+                Arc::new(Float64Array::from(vec![Some(5.0), Some(1.5), None])),
+            ],
+        )
+        .map_err(HelaError::ArrowError)?;
+        let input_file = NamedTempFile::new()?;
+        let mut writer = ArrowWriter::try_new(input_file.reopen()?, schema, None)
+            .map_err(HelaError::ParquetError)?;
+        writer.write(&batch).map_err(HelaError::ParquetError)?;
+        writer.close().map_err(HelaError::ParquetError)?;
+
+        let txns = ParquetReader::new(input_file.path().to_str().unwrap())?
+            .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(txns.len(), 3);
+        assert_eq!(txns[1].r#type, TransactionType::Withdrawal);
+        assert_eq!(txns[2].amount, None);
+
+        let mut engine = PaymentEngine::new(Box::new(InMemoryStore::new()));
+        engine.execute_transactions(txns.into_iter().map(Ok))?;
+        let output_file = NamedTempFile::new()?;
+        ParquetWriter::write_accounts(engine.accounts()?, false, output_file.reopen()?)?;
+        let batch = ParquetRecordBatchReaderBuilder::try_new(output_file.reopen()?)
+            .and_then(|builder| builder.build())
+            .map_err(HelaError::ParquetError)?
+            .next()
+            .unwrap()
+            .map_err(HelaError::ArrowError)?;
+        assert_eq!(batch.num_rows(), 1);
+        let amount = |name| {
+            batch
+                .column_by_name(name)
+                .unwrap()
+                .as_primitive::<Float64Type>()
+                .value(0)
+        };
+        assert_eq!((amount("held"), amount("total")), (5.0, 3.5));
+        Ok(())
+    }
+}
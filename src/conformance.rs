@@ -0,0 +1,340 @@
+//! Conformance checks for storage backends.
+//!
+//! Every check takes a fresh, empty store and panics if the store does not behave
+//! like [`InMemoryStore`](crate::InMemoryStore). A backend can be validated with
+//! [`run_all`] or, to get one test per check, with the
+//! [`storage_conformance_tests`](crate::storage_conformance_tests) macro:
+//!
+//! ```ignore
+//! mod conformance {
+//!     hela::storage_conformance_tests!(my_backend::Store::new());
+//! }
+//! ```
+use crate::{
+    Account, Amount, Client, Fallible, HelaError, Storage, Transaction, TransactionId,
+    TransactionType, AMOUNT_EPSILON,
+};
+
+/// Generate a `#[test]` for every conformance check, each run against the store built by `$new_store`
+#[macro_export]
+macro_rules! storage_conformance_tests {
+    // the list of checks, passed on to the rule named by `@$rule`
+    (@checks @$rule:ident $($args:tt)*) => {
+        $crate::storage_conformance_tests! {
+            @$rule $($args)*;
+            missing_account,
+            store_and_fetch_account,
+            deposit_and_withdraw,
+            credit_limit,
+            dispute_and_resolve,
+            chargeback_locks_account,
+            lock_and_unlock,
+            adjust,
+            transfer,
+            transaction_round_trip,
+            transaction_marks,
+            commit_keeps_changes,
+            rollback_discards_changes,
+            unit_of_work_misuse
+        }
+    };
+    (@tests $new_store:expr; $($check:ident),*) => {
+        $(
+            #[test]
+            fn $check() -> $crate::Fallible<()> {
+                $crate::conformance::$check($new_store)
+            }
+        )*
+    };
+    (@run_all $new_store:expr; $($check:ident),*) => {{
+        $($crate::conformance::$check($new_store())?;)*
+        Ok(())
+    }};
+    ($new_store:expr) => {
+        $crate::storage_conformance_tests!(@checks @tests $new_store);
+    };
+}
+
+/// Run every conformance check, each against a fresh store built by `new_store`
+pub fn run_all<S: Storage, F: Fn() -> S>(new_store: F) -> Fallible<()> {
+    crate::storage_conformance_tests!(@checks @run_all new_store)
+}
+
+fn deposit_txn(client: Client, id: TransactionId, amount: Amount) -> Transaction {
+    Transaction {
+        r#type: TransactionType::Deposit,
+        client,
+        id,
+        amount: Some(amount),
+        timestamp: Some(u64::from(id)),
+        destination: None,
+        disputed: 0.0,
+        disputed_at: None,
+        charged_back: 0.0,
+        refunded: 0.0,
+    }
+}
+
+fn assert_amount(actual: Amount, expected: Amount) {
+    assert!(
+        (actual - expected).abs() < AMOUNT_EPSILON,
+        "expected {}, got {}",
+        expected,
+        actual
+    );
+}
+
+/// Check a client's account balances and that they are consistent
+fn assert_account<S: Storage>(
+    store: &S,
+    client: Client,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+) -> Fallible<Account> {
+    let acc = store.get_account(client)?;
+    assert_eq!(acc.client, client);
+    assert_amount(acc.available, available);
+    assert_amount(acc.held, held);
+    assert_amount(acc.total, total);
+    assert_amount(acc.total, acc.available + acc.held);
+    Ok(acc)
+}
+
+/// Accounts that were never touched are not found
+pub fn missing_account<S: Storage>(store: S) -> Fallible<()> {
+    assert!(matches!(
+        store.get_account(1),
+        Err(HelaError::AccountNotFound(1))
+    ));
+    assert!(store.is_locked(1).is_err());
+    assert_eq!(store.get_accounts()?.count(), 0);
+    Ok(())
+}
+
+/// Stored accounts are fetched unchanged
+pub fn store_and_fetch_account<S: Storage>(mut store: S) -> Fallible<()> {
+    let acc = Account {
+        client: 7,
+        available: 10.0,
+        held: 2.5,
+        total: 12.5,
+        locked: true,
+        credit_limit: 3.0,
+    };
+    store.store_account(acc)?;
+    assert_eq!(store.get_account(7)?, acc);
+    assert!(store.is_locked(7)?);
+    assert_eq!(store.get_accounts()?.collect::<Vec<_>>(), vec![acc]);
+    Ok(())
+}
+
+/// Deposits credit an account, withdrawals debit it unless funds are insufficient
+pub fn deposit_and_withdraw<S: Storage>(mut store: S) -> Fallible<()> {
+    store.deposit(1, 10.0)?;
+    assert_account(&store, 1, 10.0, 0.0, 10.0)?;
+    store.withdraw(1, 4.0)?;
+    assert_account(&store, 1, 6.0, 0.0, 6.0)?;
+    assert!(matches!(
+        store.withdraw(1, 6.5),
+        Err(HelaError::InsufficientAccountFunds(1))
+    ));
+    assert_account(&store, 1, 6.0, 0.0, 6.0)?;
+    store.withdraw(1, 6.0)?;
+    assert_account(&store, 1, 0.0, 0.0, 0.0)?;
+    Ok(())
+}
+
+/// Withdrawals may take the available funds down to the negative credit limit
+pub fn credit_limit<S: Storage>(mut store: S) -> Fallible<()> {
+    store.deposit(1, 1.0)?;
+    store.set_credit_limit(1, 2.0)?;
+    assert_amount(store.get_account(1)?.credit_limit, 2.0);
+    store.withdraw(1, 3.0)?;
+    assert_account(&store, 1, -2.0, 0.0, -2.0)?;
+    assert!(matches!(
+        store.withdraw(1, 0.5),
+        Err(HelaError::InsufficientAccountFunds(1))
+    ));
+    assert_account(&store, 1, -2.0, 0.0, -2.0)?;
+    for limit in [-1.0, Amount::NAN, Amount::INFINITY] {
+        assert!(matches!(
+            store.set_credit_limit(1, limit),
+            Err(HelaError::InvalidCreditLimit(1, _))
+        ));
+    }
+    assert_amount(store.get_account(1)?.credit_limit, 2.0);
+    store.set_credit_limit(2, 1.0)?;
+    assert!(store.get_account(2).is_err());
+    Ok(())
+}
+
+/// Disputes move funds from available to held, resolutions move them back
+pub fn dispute_and_resolve<S: Storage>(mut store: S) -> Fallible<()> {
+    store.deposit(1, 10.0)?;
+    store.dispute(1, 4.0)?;
+    assert_account(&store, 1, 6.0, 4.0, 10.0)?;
+    store.resolve(1, 1.5)?;
+    assert_account(&store, 1, 7.5, 2.5, 10.0)?;
+    store.resolve(1, 2.5)?;
+    let acc = assert_account(&store, 1, 10.0, 0.0, 10.0)?;
+    assert!(!acc.locked);
+    Ok(())
+}
+
+/// Chargebacks remove held funds and lock the account
+pub fn chargeback_locks_account<S: Storage>(mut store: S) -> Fallible<()> {
+    store.deposit(1, 10.0)?;
+    store.dispute(1, 4.0)?;
+    store.chargeback(1, 4.0)?;
+    let acc = assert_account(&store, 1, 6.0, 0.0, 6.0)?;
+    assert!(acc.locked);
+    assert!(store.is_locked(1)?);
+    Ok(())
+}
+
+/// Accounts can be locked and unlocked, but only existing accounts unlocked
+pub fn lock_and_unlock<S: Storage>(mut store: S) -> Fallible<()> {
+    store.deposit(1, 1.0)?;
+    assert!(!store.is_locked(1)?);
+    store.lock_account(1)?;
+    assert!(store.is_locked(1)?);
+    store.unlock_account(1)?;
+    assert!(!store.is_locked(1)?);
+    assert!(matches!(
+        store.unlock_account(2),
+        Err(HelaError::AccountNotFound(2))
+    ));
+    Ok(())
+}
+
+/// Adjustments credit or debit an account regardless of its funds
+pub fn adjust<S: Storage>(mut store: S) -> Fallible<()> {
+    store.adjust(1, 5.0)?;
+    assert_account(&store, 1, 5.0, 0.0, 5.0)?;
+    store.adjust(1, -7.0)?;
+    assert_account(&store, 1, -2.0, 0.0, -2.0)?;
+    Ok(())
+}
+
+/// Transfers move funds between accounts, or fail without changing either
+pub fn transfer<S: Storage>(mut store: S) -> Fallible<()> {
+    store.deposit(1, 10.0)?;
+    store.transfer(1, 2, 4.0)?;
+    assert_account(&store, 1, 6.0, 0.0, 6.0)?;
+    assert_account(&store, 2, 4.0, 0.0, 4.0)?;
+    assert!(matches!(
+        store.transfer(2, 1, 5.0),
+        Err(HelaError::InsufficientAccountFunds(2))
+    ));
+    store.lock_account(1)?;
+    assert!(matches!(
+        store.transfer(2, 1, 1.0),
+        Err(HelaError::AccountLocked(1))
+    ));
+    assert_account(&store, 1, 6.0, 0.0, 6.0)?;
+    assert_account(&store, 2, 4.0, 0.0, 4.0)?;
+    assert!(store.transfer(3, 2, 1.0).is_err());
+    assert!(store.get_account(3).is_err());
+    Ok(())
+}
+
+/// Stored deposits are fetched unchanged, unknown transactions are not found
+pub fn transaction_round_trip<S: Storage>(mut store: S) -> Fallible<()> {
+    let txn = deposit_txn(1, 1, 2.5);
+    store.store_transaction(txn)?;
+    assert_eq!(store.get_transaction(1)?, txn);
+    assert_eq!(store.get_transaction_amount(1)?, Some(2.5));
+    assert!(matches!(
+        store.get_transaction(2),
+        Err(HelaError::TransactionNotFound(2))
+    ));
+    assert!(store.get_transaction_amount(2).is_err());
+    Ok(())
+}
+
+/// Disputed, charged back and refunded amounts are tracked per transaction
+pub fn transaction_marks<S: Storage>(mut store: S) -> Fallible<()> {
+    store.store_transaction(deposit_txn(1, 1, 10.0))?;
+    store.mark_transaction_as_disputed(1, 4.0, Some(20))?;
+    store.mark_transaction_as_disputed(1, 2.0, Some(30))?;
+    let txn = store.get_transaction(1)?;
+    assert_amount(txn.disputed, 6.0);
+    assert_eq!(txn.disputed_at, Some(20));
+    assert_amount(txn.disputable_amount(), 4.0);
+
+    store.mark_transaction_as_undisputed(1, 1.0)?;
+    store.mark_transaction_as_charged_back(1, 2.0)?;
+    store.mark_transaction_as_refunded(1, 3.0)?;
+    let txn = store.get_transaction(1)?;
+    assert_amount(txn.disputed, 3.0);
+    assert_amount(txn.charged_back, 2.0);
+    assert_amount(txn.refunded, 3.0);
+    assert_amount(txn.disputable_amount(), 2.0);
+
+    store.mark_transaction_as_undisputed(1, 3.0)?;
+    let txn = store.get_transaction(1)?;
+    assert_amount(txn.disputed, 0.0);
+    assert_eq!(txn.disputed_at, None);
+
+    assert!(matches!(
+        store.mark_transaction_as_disputed(2, 1.0, None),
+        Err(HelaError::TransactionNotFound(2))
+    ));
+    assert!(store.mark_transaction_as_undisputed(2, 1.0).is_err());
+    assert!(store.mark_transaction_as_charged_back(2, 1.0).is_err());
+    assert!(store.mark_transaction_as_refunded(2, 1.0).is_err());
+    Ok(())
+}
+
+/// Changes made in a committed unit of work are kept
+pub fn commit_keeps_changes<S: Storage>(mut store: S) -> Fallible<()> {
+    store.begin()?;
+    store.deposit(1, 5.0)?;
+    store.store_transaction(deposit_txn(1, 1, 5.0))?;
+    store.commit()?;
+    assert_account(&store, 1, 5.0, 0.0, 5.0)?;
+    assert_eq!(store.get_transaction(1)?, deposit_txn(1, 1, 5.0));
+    Ok(())
+}
+
+/// Changes made in a rolled back unit of work are discarded
+pub fn rollback_discards_changes<S: Storage>(mut store: S) -> Fallible<()> {
+    store.deposit(1, 5.0)?;
+    store.store_transaction(deposit_txn(1, 1, 5.0))?;
+
+    store.begin()?;
+    store.dispute(1, 5.0)?;
+    store.mark_transaction_as_disputed(1, 5.0, None)?;
+    store.set_credit_limit(1, 1.0)?;
+    store.set_credit_limit(4, 1.0)?;
+    store.transfer(1, 2, 1.0)?;
+    store.store_account(Account {
+        client: 3,
+        ..Default::default()
+    })?;
+    store.store_transaction(deposit_txn(3, 2, 1.0))?;
+    store.rollback()?;
+
+    let acc = assert_account(&store, 1, 5.0, 0.0, 5.0)?;
+    assert_amount(acc.credit_limit, 0.0);
+    assert_eq!(store.get_transaction(1)?, deposit_txn(1, 1, 5.0));
+    assert!(store.get_account(2).is_err());
+    assert!(store.get_account(3).is_err());
+    assert!(store.get_transaction(2).is_err());
+    assert_eq!(store.get_accounts()?.count(), 1);
+    store.deposit(4, 1.0)?;
+    assert_amount(store.get_account(4)?.credit_limit, 0.0);
+    Ok(())
+}
+
+/// Units of work cannot be nested, nor finished without being started
+pub fn unit_of_work_misuse<S: Storage>(mut store: S) -> Fallible<()> {
+    assert!(store.commit().is_err());
+    assert!(store.rollback().is_err());
+    store.begin()?;
+    assert!(store.begin().is_err());
+    store.commit()?;
+    assert!(store.commit().is_err());
+    Ok(())
+}
@@ -2,7 +2,7 @@
 
 use serde::{Deserialize, Serialize, Serializer};
 
-use crate::errors::Fallible;
+use crate::errors::{Fallible, HelaError};
 
 /// Client Identifier
 pub type Client = u16;
@@ -10,6 +10,13 @@ pub type Client = u16;
 pub type Amount = f64;
 /// Transaction Identifier
 pub type TransactionId = u32;
+/// Timestamp, in seconds since the Unix epoch
+pub type Timestamp = u64;
+
+/// Largest difference between amounts that is treated as a rounding error
+pub const AMOUNT_EPSILON: Amount = 1e-9;
+/// Largest amount accepted in a single transaction
+pub const MAX_AMOUNT: Amount = 1e12;
 
 /// Transaction Type
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -25,6 +32,57 @@ pub enum TransactionType {
     Resolve,
     /// Chargeback
     Chargeback,
+    /// Refund of part or all of a deposit
+    Refund,
+    /// Transfer of funds from the client to a destination client
+    Transfer,
+    /// Administrative unlock of a locked account
+    Unlock,
+    /// Administrative credit (positive amount) or debit (negative amount)
+    Adjust,
+    /// Administrative account closure, paying out the available funds
+    Close,
+}
+
+impl TransactionType {
+    /// Every transaction type. Binary files store a type as its index here, so new types
+    /// go at the end.
+    pub const ALL: [TransactionType; 10] = [
+        TransactionType::Withdrawal,
+        TransactionType::Deposit,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Refund,
+        TransactionType::Transfer,
+        TransactionType::Unlock,
+        TransactionType::Adjust,
+        TransactionType::Close,
+    ];
+
+    /// Type name, as used in input files
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Refund => "refund",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Adjust => "adjust",
+            TransactionType::Close => "close",
+        }
+    }
+
+    /// Whether the transaction type may only be submitted by an administrator
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Unlock | TransactionType::Adjust | TransactionType::Close
+        )
+    }
 }
 
 /// Transaction
@@ -39,10 +97,64 @@ pub struct Transaction {
     /// Optional Amount
     #[serde(default)]
     pub amount: Option<Amount>,
-    /// Transaction is disputed
+    /// Optional Timestamp
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+    /// Destination client of a transfer
+    #[serde(default)]
+    pub destination: Option<Client>,
+    /// Amount of the transaction under dispute
     #[serde(default)]
     #[serde(skip)]
-    pub disputed: bool,
+    pub disputed: Amount,
+    /// Timestamp of the earliest open dispute raised against the transaction
+    #[serde(default)]
+    #[serde(skip)]
+    pub disputed_at: Option<Timestamp>,
+    /// Amount of the transaction charged back
+    #[serde(default)]
+    #[serde(skip)]
+    pub charged_back: Amount,
+    /// Amount of the transaction refunded
+    #[serde(default)]
+    #[serde(skip)]
+    pub refunded: Amount,
+}
+
+impl Transaction {
+    /// Amount of the transaction that can still be disputed or refunded
+    pub fn disputable_amount(&self) -> Amount {
+        self.amount.unwrap_or_default() - self.disputed - self.charged_back - self.refunded
+    }
+}
+
+/// Administrative transaction, read from a separately authorised input file
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AdminTransaction {
+    /// Transaction Type
+    pub r#type: TransactionType,
+    /// Client
+    pub client: Client,
+    /// Transaction Identifier
+    #[serde(rename = "tx")]
+    pub id: TransactionId,
+    /// Optional Amount, required by adjustments
+    #[serde(default)]
+    pub amount: Option<Amount>,
+    /// Reason for the operation, recorded in the audit trail
+    #[serde(default)]
+    pub reason: String,
+}
+
+/// A transaction that was not applied, and why
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Rejection {
+    /// Client
+    pub client: Client,
+    /// Transaction Identifier
+    pub tx: TransactionId,
+    /// Reason the transaction was rejected
+    pub reason: String,
 }
 
 /// Account
@@ -61,22 +173,123 @@ pub struct Account {
     pub total: Amount,
     /// Locked status
     pub locked: bool,
+    /// Credit limit, how far below zero the available amount may be withdrawn
+    #[serde(default, skip_serializing)]
+    pub credit_limit: Amount,
 }
 
+/// Credit limit granted to a client's account
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct CreditLimit {
+    /// Client
+    pub client: Client,
+    /// Credit limit
+    pub credit_limit: Amount,
+}
+
+/// Client owning a bank account named in statements
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct StatementAccount {
+    /// Account IBAN or other identifier
+    pub account: String,
+    /// Client
+    pub client: Client,
+}
+
+/// Largest difference between an account's total and the sum of its available and held
+/// amounts once all three are rounded to cents in the output
+const OUTPUT_ROUNDING: Amount = 0.015;
+
 impl Account {
-    #[cfg(debug_assertions)]
     /// check that account invariants are not violated
     pub fn check_invariants(&self) {
-        assert!(self.total >= self.available);
-        assert!((self.total - (self.available + self.held)).abs() < f64::EPSILON);
+        if let Some(violation) = self.violation(0.0) {
+            panic!("account {} violates invariants: {}", self.client, violation);
+        }
+    }
+
+    /// Invariant violated by the account, allowing its amounts to be off by `slack`
+    fn violation(&self, slack: Amount) -> Option<&'static str> {
+        // amounts are floats, so allow for rounding errors relative to the balances
+        let tolerance =
+            slack + AMOUNT_EPSILON * self.total.abs().max(self.available.abs()).max(1.0);
+        if ![self.available, self.held, self.total, self.credit_limit]
+            .iter()
+            .all(|amount| amount.is_finite())
+        {
+            Some("amounts must be finite")
+        } else if self.credit_limit < 0.0 {
+            Some("credit limit is negative")
+        } else if self.total < self.available - tolerance {
+            Some("held amount is negative")
+        } else if (self.total - (self.available + self.held)).abs() > tolerance {
+            Some("total is not the sum of the available and held amounts")
+        } else {
+            None
+        }
+    }
+
+    /// Check an account read back from the accounts output as an opening balance, its total
+    /// being recomputed from the available and held amounts, which are rounded to cents.
+    /// Accounts holding funds are rejected, as the disputes that would release them are not
+    /// carried over.
+    pub fn into_opening_balance(mut self) -> Fallible<Account> {
+        if let Some(violation) = self.violation(OUTPUT_ROUNDING) {
+            return Err(HelaError::InvalidOpeningBalance(self.client, violation));
+        }
+        if self.held != 0.0 {
+            return Err(HelaError::InvalidOpeningBalance(
+                self.client,
+                "held funds cannot be carried over without their disputes",
+            ));
+        }
+        self.total = self.available + self.held;
+        Ok(self)
     }
 }
 
+/// Memory used by a storage backend
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct MemoryUsage {
+    /// Number of accounts stored
+    pub accounts: usize,
+    /// Number of transactions retained
+    pub transactions: usize,
+    /// Estimated heap memory used, in bytes
+    pub bytes: usize,
+}
+
 /// A combined storage interface for Accounts and Transactions
-pub trait Storage: AccountStore + TransactionStore {}
+///
+/// Changes are made in units of work: every change made between `begin` and `commit`
+/// is applied as one, and every change made between `begin` and `rollback` is discarded.
+pub trait Storage: AccountStore + TransactionStore {
+    /// Start a unit of work
+    fn begin(&mut self) -> Fallible<()>;
+
+    /// Apply the changes made since the unit of work was started
+    fn commit(&mut self) -> Fallible<()>;
+
+    /// Discard the changes made since the unit of work was started
+    fn rollback(&mut self) -> Fallible<()>;
+
+    /// Memory used by the storage backend, if it can be measured
+    fn memory_usage(&self) -> Option<MemoryUsage> {
+        None
+    }
+}
+
+/// A source of transactions in input order, e.g. a CSV or a JSON Lines file
+pub trait TransactionSource: Iterator<Item = Fallible<Transaction>> {}
 
 /// Trait providing interface to be implemented by storage backend
 pub trait AccountStore {
+    /// An adjustment is a manual credit or debit of the client's account by an administrator.
+    ///
+    /// This means that the clients available and total funds should change by the amount,
+    /// regardless of the funds available.
+    fn adjust(&mut self, client_id: Client, amount: Amount) -> Fallible<()>;
+
     /// Check if an account has been locked.
     fn is_locked(&self, client_id: Client) -> Fallible<bool>;
 
@@ -112,11 +325,31 @@ pub trait AccountStore {
     /// and their total funds should remain the same.
     fn resolve(&mut self, client_id: Client, amount: Amount) -> Fallible<()>;
 
+    /// Set the credit limit of a client's account.
+    ///
+    /// Withdrawals are allowed to take the available funds down to `-limit`. A limit set before
+    /// the client's first transaction applies once the account is created. Negative and
+    /// non-finite limits are rejected.
+    fn set_credit_limit(&mut self, client_id: Client, limit: Amount) -> Fallible<()>;
+
+    /// Unlocks an Account
+    fn unlock_account(&mut self, client_id: Client) -> Fallible<()>;
+
+    /// A transfer moves funds from one client's account to another's, as a single unit.
+    ///
+    /// This means that the source's available and total funds should decrease by the amount,
+    /// and the destination's should increase by it. The transfer fails without changing either
+    /// account if the source has insufficient funds or either account is locked.
+    fn transfer(&mut self, from: Client, to: Client, amount: Amount) -> Fallible<()>;
+
     /// Persist account in the storage backend
     fn store_account(&mut self, acc: Account) -> Fallible<()>;
 
     /// A withdraw is a debit to the client's asset account, meaning it
     /// should decrease the available and total funds of the client account
+    ///
+    /// The withdrawal fails if it would take the available funds below the
+    /// negative of the account's credit limit.
     fn withdraw(&mut self, client_id: Client, amount: Amount) -> Fallible<()>;
 
     /// All accounts stored by the storage backend.
@@ -131,18 +364,36 @@ pub trait TransactionStore {
     /// Fetch a Transaction Amount
     fn get_transaction_amount(&self, id: TransactionId) -> Fallible<Option<Amount>>;
 
-    /// Persist a transaction in the storage backend
+    /// Persist a transaction in the storage backend.
+    ///
+    /// Backends may choose to only keep transactions that can later be disputed.
     fn store_transaction(&mut self, txn: Transaction) -> Fallible<()>;
 
-    /// Mark a transaction as disputed
-    fn mark_transaction_as_disputed(&mut self, id: TransactionId) -> Fallible<()>;
+    /// Mark an amount of a transaction as disputed, by a dispute with an optional timestamp
+    fn mark_transaction_as_disputed(
+        &mut self,
+        id: TransactionId,
+        amount: Amount,
+        at: Option<Timestamp>,
+    ) -> Fallible<()>;
+
+    /// Mark a disputed amount of a transaction as undisputed
+    fn mark_transaction_as_undisputed(&mut self, id: TransactionId, amount: Amount)
+        -> Fallible<()>;
+
+    /// Mark an amount of a transaction as refunded
+    fn mark_transaction_as_refunded(&mut self, id: TransactionId, amount: Amount) -> Fallible<()>;
 
-    /// Mark a transaction as undisputed
-    fn mark_transaction_as_undisputed(&mut self, id: TransactionId) -> Fallible<()>;
+    /// Mark a disputed amount of a transaction as charged back
+    fn mark_transaction_as_charged_back(
+        &mut self,
+        id: TransactionId,
+        amount: Amount,
+    ) -> Fallible<()>;
 }
 
 /// Serialize floats
 pub fn ser_float<S: Serializer>(float: &f64, serializer: S) -> Result<S::Ok, S::Error> {
     let float_as_str = format!("{:.2}", float);
-    serializer.serialize_str(&&float_as_str)
+    serializer.serialize_str(&float_as_str)
 }
@@ -1,36 +1,553 @@
+use std::collections::HashMap;
 use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::marker::PhantomData;
+use std::path::Path;
 
-use crate::{Account, Fallible, HelaError, Transaction};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    ser_float, Account, Amount, BinaryReader, Camt053Reader, Client, CsvDialect, Fallible,
+    HelaError, ParallelCsvReader, StatementIds, Timestamp, Transaction, TransactionId,
+    TransactionSource, TransactionType, BINARY_MAGIC,
+};
 
 /// CSV Data Reader
-pub struct CsvDataReader(csv::Reader<File>);
+///
+/// Files whose header only names known columns (`type`, `client`, `tx`, `amount`, `timestamp`
+/// and `destination`, in any order) are parsed directly from a reused `ByteRecord`.
+/// Other files are deserialized by position with serde.
+pub struct CsvDataReader<R = File> {
+    rdr: csv::Reader<R>,
+    dialect: CsvDialect,
+    record: csv::ByteRecord,
+    string_record: csv::StringRecord,
+    columns: Option<Option<Columns>>,
+}
 
 impl CsvDataReader {
     /// Create new reader from a path
     pub fn new(fname: &str) -> Fallible<CsvDataReader> {
-        let rdr = csv::ReaderBuilder::new()
-            .flexible(true)
-            .trim(csv::Trim::All)
+        CsvDataReader::with_dialect(fname, &CsvDialect::default())
+    }
+
+    /// Create new reader from a path, of a given dialect
+    pub fn with_dialect(fname: &str, dialect: &CsvDialect) -> Fallible<CsvDataReader> {
+        dialect.validate()?;
+        let rdr = dialect
+            .reader_builder()
             .from_path(fname)
             .map_err(HelaError::CsvError)?;
-        Ok(CsvDataReader(rdr))
+        Ok(CsvDataReader::with_reader(rdr, dialect))
+    }
+}
+
+impl<R: Read> CsvDataReader<R> {
+    /// Create new reader over any source of CSV data, e.g. stdin or a buffer
+    pub fn from_reader(rdr: R) -> CsvDataReader<R> {
+        CsvDataReader::from_reader_with_dialect(rdr, &CsvDialect::default())
+    }
+
+    /// Create new reader over any source of CSV data, of a given dialect
+    ///
+    /// The dialect is expected to be valid, see [`CsvDialect::validate`].
+    pub fn from_reader_with_dialect(rdr: R, dialect: &CsvDialect) -> CsvDataReader<R> {
+        CsvDataReader::with_reader(dialect.reader_builder().from_reader(rdr), dialect)
+    }
+
+    fn with_reader(rdr: csv::Reader<R>, dialect: &CsvDialect) -> CsvDataReader<R> {
+        CsvDataReader {
+            rdr,
+            dialect: dialect.clone(),
+            record: csv::ByteRecord::new(),
+            string_record: csv::StringRecord::new(),
+            columns: None,
+        }
+    }
+
+    /// Column indexes of the header, if the fast path can parse it
+    fn columns(&mut self) -> Fallible<Option<Columns>> {
+        if let Some(columns) = self.columns {
+            return Ok(columns);
+        }
+        let columns = if self.dialect.headers {
+            let headers = self.rdr.byte_headers().map_err(HelaError::CsvError)?;
+            Columns::from_headers(headers, &self.dialect)
+        } else {
+            Some(Columns::positional())
+        };
+        self.columns = Some(columns);
+        Ok(columns)
+    }
+
+    /// Byte offset of the first record after the header
+    pub(crate) fn header_end(&mut self) -> Fallible<u64> {
+        self.columns()?;
+        Ok(self.rdr.position().byte())
+    }
+
+    /// Continue reading at `from`, reporting positions from `pos`
+    pub(crate) fn seek(&mut self, from: SeekFrom, pos: csv::Position) -> Fallible<()>
+    where
+        R: Seek,
+    {
+        self.columns()?;
+        self.rdr.seek_raw(from, pos).map_err(HelaError::CsvError)
+    }
+
+    fn read(&mut self) -> Fallible<Option<Transaction>> {
+        let columns = match self.columns()? {
+            Some(columns) => columns,
+            None => return self.read_with_serde(),
+        };
+        if !self
+            .rdr
+            .read_byte_record(&mut self.record)
+            .map_err(HelaError::CsvError)?
+        {
+            return Ok(None);
+        }
+        match columns.parse(&self.record, &self.dialect) {
+            Some(txn) => Ok(Some(txn)),
+            // let serde parse what it can and report what is wrong with the record
+            None => {
+                let rec = columns.reorder(&self.record);
+                match csv::StringRecord::from_byte_record(rec) {
+                    Ok(mut rec) => {
+                        rec.trim();
+                        self.dialect.normalize(&mut rec);
+                        rec.deserialize(None)
+                    }
+                    Err(e) => e.into_byte_record().deserialize(None),
+                }
+                .map(Some)
+                .map_err(HelaError::CsvError)
+            }
+        }
+    }
+
+    fn read_with_serde(&mut self) -> Fallible<Option<Transaction>> {
+        if !self
+            .rdr
+            .read_record(&mut self.string_record)
+            .map_err(HelaError::CsvError)?
+        {
+            return Ok(None);
+        }
+        self.string_record.trim();
+        self.dialect.normalize(&mut self.string_record);
+        self.string_record
+            .deserialize(None)
+            .map(Some)
+            .map_err(HelaError::CsvError)
     }
 }
 
-impl Iterator for CsvDataReader {
+impl<R: Read> Iterator for CsvDataReader<R> {
     type Item = Fallible<Transaction>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let mut rec = csv::StringRecord::new();
-        match self.0.read_record(&mut rec) {
-            Err(e) => Some(Err(HelaError::CsvError(e))),
-            Ok(rec_read) => {
-                if rec_read {
-                    Some(rec.deserialize(None).map_err(HelaError::CsvError))
-                } else {
-                    None
+        self.read().transpose()
+    }
+}
+
+impl<R: Read> TransactionSource for CsvDataReader<R> {}
+
+/// Indexes of the transaction columns within a record
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct Columns {
+    r#type: usize,
+    client: usize,
+    tx: usize,
+    amount: Option<usize>,
+    timestamp: Option<usize>,
+    destination: Option<usize>,
+}
+
+impl Columns {
+    fn from_headers(headers: &csv::ByteRecord, dialect: &CsvDialect) -> Option<Columns> {
+        let mut found = [None; 6];
+        for (idx, header) in headers.iter().enumerate() {
+            let slot = match dialect.column(header) {
+                b"type" => 0,
+                b"client" => 1,
+                b"tx" => 2,
+                b"amount" => 3,
+                b"timestamp" => 4,
+                b"destination" => 5,
+                _ => return None,
+            };
+            if found[slot].replace(idx).is_some() {
+                return None;
+            }
+        }
+        Some(Columns {
+            r#type: found[0]?,
+            client: found[1]?,
+            tx: found[2]?,
+            amount: found[3],
+            timestamp: found[4],
+            destination: found[5],
+        })
+    }
+
+    /// Columns of a file without a header
+    fn positional() -> Columns {
+        Columns {
+            r#type: 0,
+            client: 1,
+            tx: 2,
+            amount: Some(3),
+            timestamp: Some(4),
+            destination: Some(5),
+        }
+    }
+
+    /// Parse a record, or None if it is not well formed
+    fn parse(&self, rec: &csv::ByteRecord, dialect: &CsvDialect) -> Option<Transaction> {
+        let field =
+            |idx: Option<usize>| trim_bytes(idx.and_then(|idx| rec.get(idx)).unwrap_or_default());
+        Some(Transaction {
+            r#type: dialect.parse_type(field(Some(self.r#type)))?,
+            client: parse(field(Some(self.client)))?,
+            id: parse(field(Some(self.tx)))?,
+            amount: dialect.parse_amount(field(self.amount))?,
+            timestamp: parse_optional(field(self.timestamp))?,
+            destination: parse_optional(field(self.destination))?,
+            disputed: 0.0,
+            disputed_at: None,
+            charged_back: 0.0,
+            refunded: 0.0,
+        })
+    }
+
+    /// Fields of a record in the positional order serde expects
+    fn reorder(&self, rec: &csv::ByteRecord) -> csv::ByteRecord {
+        let mut reordered: csv::ByteRecord = [
+            Some(self.r#type),
+            Some(self.client),
+            Some(self.tx),
+            self.amount,
+            self.timestamp,
+            self.destination,
+        ]
+        .iter()
+        // fields missing from a short record end it, columns missing from the header are empty
+        .map_while(|idx| match idx {
+            Some(idx) => rec.get(*idx),
+            None => Some(&b""[..]),
+        })
+        .collect();
+        reordered.set_position(rec.position().cloned());
+        reordered
+    }
+}
+
+pub(crate) fn parse_type(field: &[u8]) -> Option<TransactionType> {
+    TransactionType::ALL
+        .iter()
+        .copied()
+        .find(|r#type| r#type.name().as_bytes() == field)
+}
+
+fn parse<T: std::str::FromStr>(field: &[u8]) -> Option<T> {
+    std::str::from_utf8(field).ok()?.parse().ok()
+}
+
+/// Strip leading and trailing ASCII whitespace from a field
+fn trim_bytes(mut field: &[u8]) -> &[u8] {
+    while let [first, rest @ ..] = field {
+        if !first.is_ascii_whitespace() {
+            break;
+        }
+        field = rest;
+    }
+    while let [rest @ .., last] = field {
+        if !last.is_ascii_whitespace() {
+            break;
+        }
+        field = rest;
+    }
+    field
+}
+
+/// Empty fields are None, unparseable ones fail the record
+pub(crate) fn parse_optional<T: std::str::FromStr>(field: &[u8]) -> Option<Option<T>> {
+    if field.is_empty() {
+        Some(None)
+    } else {
+        parse(field).map(Some)
+    }
+}
+
+/// Transaction line of a JSON Lines file, named like the CSV columns
+#[derive(Deserialize)]
+struct JsonTransaction {
+    r#type: TransactionType,
+    client: Client,
+    tx: TransactionId,
+    #[serde(default)]
+    amount: Option<Amount>,
+    #[serde(default)]
+    timestamp: Option<Timestamp>,
+    #[serde(default)]
+    destination: Option<Client>,
+}
+
+impl From<JsonTransaction> for Transaction {
+    fn from(txn: JsonTransaction) -> Self {
+        Transaction {
+            r#type: txn.r#type,
+            client: txn.client,
+            id: txn.tx,
+            amount: txn.amount,
+            timestamp: txn.timestamp,
+            destination: txn.destination,
+            disputed: 0.0,
+            disputed_at: None,
+            charged_back: 0.0,
+            refunded: 0.0,
+        }
+    }
+}
+
+/// JSON Lines Data Reader, one transaction object per line
+pub struct JsonLinesReader<R = File> {
+    rdr: BufReader<R>,
+    line: String,
+    line_number: u64,
+}
+
+impl JsonLinesReader {
+    /// Create new reader from a path
+    pub fn new(fname: &str) -> Fallible<JsonLinesReader> {
+        Ok(JsonLinesReader::from_reader(File::open(fname)?))
+    }
+}
+
+impl<R: Read> JsonLinesReader<R> {
+    /// Create new reader over any source of JSON Lines data
+    pub fn from_reader(rdr: R) -> JsonLinesReader<R> {
+        JsonLinesReader {
+            rdr: BufReader::new(rdr),
+            line: String::new(),
+            line_number: 0,
+        }
+    }
+
+    fn read(&mut self) -> Fallible<Option<Transaction>> {
+        loop {
+            self.line.clear();
+            if self.rdr.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            if !self.line.trim().is_empty() {
+                break;
+            }
+        }
+        serde_json::from_str::<JsonTransaction>(&self.line)
+            .map(|txn| Some(txn.into()))
+            .map_err(|e| HelaError::JsonError(self.line_number, e))
+    }
+}
+
+impl<R: Read> Iterator for JsonLinesReader<R> {
+    type Item = Fallible<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+impl<R: Read> TransactionSource for JsonLinesReader<R> {}
+
+/// Format of a transactions input file
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InputFormat {
+    /// Comma separated values with a header row
+    Csv,
+    /// One JSON object per line
+    JsonLines,
+    /// ISO 20022 camt.053 bank statement
+    Camt053,
+    /// Apache Parquet
+    Parquet,
+    /// Hela binary format
+    Binary,
+}
+
+/// Options for opening a transactions input file
+#[derive(Clone, Debug, PartialEq)]
+pub struct InputOptions {
+    /// Number of threads parsing CSV files, above one the file is read with `ParallelCsvReader`
+    pub threads: usize,
+    /// Client of each account named in bank statements
+    pub statement_accounts: HashMap<String, Client>,
+    /// Ids and references of bank statement entries, shared by the statements of a run
+    pub statement_ids: StatementIds,
+    /// Dialect of CSV files
+    pub dialect: CsvDialect,
+}
+
+impl Default for InputOptions {
+    fn default() -> Self {
+        InputOptions {
+            threads: 1,
+            statement_accounts: HashMap::new(),
+            statement_ids: StatementIds::default(),
+            dialect: CsvDialect::default(),
+        }
+    }
+}
+
+impl InputFormat {
+    /// Detect the format of a file from its extension, or else from its first character.
+    /// JSON arrays are not supported, only JSON Lines.
+    pub fn detect(fname: &str) -> Fallible<InputFormat> {
+        match Path::new(fname).extension().and_then(|ext| ext.to_str()) {
+            Some("csv") => return Ok(InputFormat::Csv),
+            Some("jsonl") | Some("ndjson") => return Ok(InputFormat::JsonLines),
+            Some("xml") => return Ok(InputFormat::Camt053),
+            Some("parquet") => return Ok(InputFormat::Parquet),
+            Some("hela") => return Ok(InputFormat::Binary),
+            _ => {}
+        }
+        let mut rdr = BufReader::new(File::open(fname)?);
+        let start = rdr.fill_buf()?;
+        if start.starts_with(b"PAR1") {
+            return Ok(InputFormat::Parquet);
+        }
+        if start.starts_with(BINARY_MAGIC) {
+            return Ok(InputFormat::Binary);
+        }
+        let first = loop {
+            let buf = rdr.fill_buf()?;
+            match buf.iter().position(|byte| !byte.is_ascii_whitespace()) {
+                Some(idx) => break Some(buf[idx]),
+                None if buf.is_empty() => break None,
+                None => {
+                    let len = buf.len();
+                    rdr.consume(len);
                 }
             }
+        };
+        Ok(match first {
+            Some(b'{') => InputFormat::JsonLines,
+            Some(b'<') => InputFormat::Camt053,
+            Some(b'[') => {
+                return Err(HelaError::UnsupportedInputFormat(
+                    fname.to_string(),
+                    "JSON arrays are not supported, use JSON Lines",
+                ))
+            }
+            _ => InputFormat::Csv,
+        })
+    }
+
+    /// Open a file of this format
+    pub fn open(self, fname: &str, options: &InputOptions) -> Fallible<Box<dyn TransactionSource>> {
+        Ok(match self {
+            // files are memory-mapped and split between threads only when asked to
+            InputFormat::Csv if options.threads > 1 => Box::new(ParallelCsvReader::with_dialect(
+                fname,
+                options.threads,
+                &options.dialect,
+            )?),
+            InputFormat::Csv => Box::new(CsvDataReader::with_dialect(fname, &options.dialect)?),
+            InputFormat::JsonLines => Box::new(JsonLinesReader::new(fname)?),
+            InputFormat::Camt053 => Box::new(
+                Camt053Reader::new(fname, options.statement_accounts.clone())?
+                    .with_ids(options.statement_ids.clone()),
+            ),
+            #[cfg(feature = "parquet")]
+            InputFormat::Parquet => Box::new(crate::ParquetReader::new(fname)?),
+            #[cfg(not(feature = "parquet"))]
+            InputFormat::Parquet => return Err(HelaError::UnsupportedFormat("Parquet")),
+            InputFormat::Binary => Box::new(BinaryReader::new(fname)?),
+        })
+    }
+}
+
+/// CSV Reader for auxiliary records, e.g. credit limits
+pub struct CsvRecordReader<T> {
+    rdr: csv::Reader<File>,
+    _record: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> CsvRecordReader<T> {
+    /// Create new reader from a path
+    pub fn new(fname: &str) -> Fallible<CsvRecordReader<T>> {
+        let rdr = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_path(fname)
+            .map_err(HelaError::CsvError)?;
+        Ok(CsvRecordReader {
+            rdr,
+            _record: PhantomData,
+        })
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for CsvRecordReader<T> {
+    type Item = Fallible<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rdr
+            .deserialize()
+            .next()
+            .map(|rec| rec.map_err(HelaError::CsvError))
+    }
+}
+
+/// Account row including the credit limit column
+#[derive(Serialize)]
+struct CreditAccount {
+    client: Client,
+    #[serde(serialize_with = "ser_float")]
+    available: Amount,
+    #[serde(serialize_with = "ser_float")]
+    held: Amount,
+    #[serde(serialize_with = "ser_float")]
+    total: Amount,
+    locked: bool,
+    #[serde(serialize_with = "ser_float")]
+    credit_limit: Amount,
+}
+
+impl From<Account> for CreditAccount {
+    fn from(acc: Account) -> Self {
+        CreditAccount {
+            client: acc.client,
+            available: acc.available,
+            held: acc.held,
+            total: acc.total,
+            locked: acc.locked,
+            credit_limit: acc.credit_limit,
+        }
+    }
+}
+
+/// Transaction row in the input format
+#[derive(Serialize)]
+struct TransactionRow {
+    r#type: TransactionType,
+    client: Client,
+    tx: TransactionId,
+    amount: Option<Amount>,
+    timestamp: Option<Timestamp>,
+    destination: Option<Client>,
+}
+
+impl From<Transaction> for TransactionRow {
+    fn from(txn: Transaction) -> Self {
+        TransactionRow {
+            r#type: txn.r#type,
+            client: txn.client,
+            tx: txn.id,
+            amount: txn.amount,
+            timestamp: txn.timestamp,
+            destination: txn.destination,
         }
     }
 }
@@ -39,45 +556,158 @@ impl Iterator for CsvDataReader {
 pub struct CsvWriterStdout;
 
 impl CsvWriterStdout {
-    #[cfg(not(debug_assertions))]
     /// Write accounts to stdout
     pub fn write<W: std::io::Write>(
         accounts: Box<dyn Iterator<Item = Account> + '_>,
         wtr: Option<W>,
     ) -> Fallible<()> {
-        let mut writer = if let Some(w) = wtr {
-            csv::Writer::from_writer(w)
-        } else {
-            csv::Writer::from_writer(std::io::stdout())
-        };
-        for acc in accounts {
-            writer.serialize(acc).map_err(HelaError::CsvError)?;
-        }
-        writer.flush()?;
-        Ok(())
+        Self::write_records(Self::ordered(accounts), wtr)
     }
 
-    #[cfg(debug_assertions)]
-    /// Write accounts to stdout
-    pub fn write<W: std::io::Write>(
+    /// Write accounts to stdout, with an additional `credit_limit` column
+    pub fn write_with_credit_limit<W: std::io::Write>(
         accounts: Box<dyn Iterator<Item = Account> + '_>,
         wtr: Option<W>,
     ) -> Fallible<()> {
+        Self::write_records(Self::ordered(accounts).map(CreditAccount::from), wtr)
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn ordered(
+        accounts: Box<dyn Iterator<Item = Account> + '_>,
+    ) -> Box<dyn Iterator<Item = Account> + '_> {
+        accounts
+    }
+
+    #[cfg(debug_assertions)]
+    fn ordered(
+        accounts: Box<dyn Iterator<Item = Account> + '_>,
+    ) -> Box<dyn Iterator<Item = Account> + '_> {
         let mut accounts: Vec<_> = accounts.collect();
         accounts.sort_by_key(|acc| acc.client);
+        Box::new(accounts.into_iter())
+    }
+
+    /// Write transactions to stdout in the input format
+    pub fn write_transactions<W: std::io::Write>(
+        txns: impl Iterator<Item = Transaction>,
+        wtr: Option<W>,
+    ) -> Fallible<()> {
+        Self::write_records(txns.map(TransactionRow::from), wtr)
+    }
+
+    /// Write arbitrary records, e.g. alerts, to stdout
+    pub fn write_records<T: Serialize, W: std::io::Write>(
+        records: impl Iterator<Item = T>,
+        wtr: Option<W>,
+    ) -> Fallible<()> {
         if let Some(w) = wtr {
-            let mut writer = csv::Writer::from_writer(w);
-            for acc in accounts {
-                writer.serialize(acc).map_err(HelaError::CsvError)?;
-            }
-            writer.flush()?;
+            Self::serialize_records(csv::Writer::from_writer(w), records)
         } else {
-            let mut writer = csv::Writer::from_writer(std::io::stdout());
-            for acc in accounts {
-                writer.serialize(acc).map_err(HelaError::CsvError)?;
-            }
-            writer.flush()?;
+            Self::serialize_records(csv::Writer::from_writer(std::io::stdout()), records)
+        }
+    }
+
+    fn serialize_records<T: Serialize, W: std::io::Write>(
+        mut writer: csv::Writer<W>,
+        records: impl Iterator<Item = T>,
+    ) -> Fallible<()> {
+        for rec in records {
+            writer.serialize(rec).map_err(HelaError::CsvError)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Workload, WorkloadGenerator};
+
+    fn read(data: &str) -> Vec<Result<Transaction, String>> {
+        CsvDataReader::from_reader(data.as_bytes())
+            .map(|txn| txn.map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn type_names_match_serde() {
+        for r#type in TransactionType::ALL.iter() {
+            let name = serde_json::to_string(r#type).unwrap();
+            assert_eq!(name, format!("\"{}\"", r#type.name()));
+            assert_eq!(parse_type(r#type.name().as_bytes()), Some(*r#type));
+        }
+    }
+
+    #[test]
+    fn fast_path_matches_serde() {
+        let mut data = vec![];
+        let workload = Workload {
+            rows: 1_000,
+            dispute_rate: 0.5,
+            ..Default::default()
         };
+        CsvWriterStdout::write_transactions(WorkloadGenerator::new(workload), Some(&mut data))
+            .unwrap();
+        let mut rows = String::from_utf8(data).unwrap();
+        rows.push_str(
+            "transfer,1,1001,2.5,100,2\ndeposit, 0x10 ,1002,1\nwithdrawal,1,1003\n\
+             deposit,1,1004,abc\nrefund,1\nbogus,1,1005,1.0\ndispute,1,1,,,,extra\n",
+        );
+        let body = &rows[rows.find('\n').unwrap()..];
+        let fast = read(&format!(
+            "type,client,tx,amount,timestamp,destination{}",
+            body
+        ));
+        // an unknown column name forces the serde path
+        let serde = read(&format!(
+            "type,client,tx,amount,timestamp,Destination{}",
+            body
+        ));
+        assert_eq!(fast.len(), 1_007);
+        assert_eq!(fast, serde);
+        assert_eq!(fast.iter().filter(|txn| txn.is_err()).count(), 3);
+    }
+
+    #[test]
+    fn reads_json_lines() {
+        let data = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 2.5}\n\n\
+                    {\"type\": \"dispute\", \"client\": 1, \"tx\": 1, \"note\": \"ignored\"}\n\
+                    {\"type\": \"deposit\", \"client\": 1}\n";
+        let txns: Vec<_> = JsonLinesReader::from_reader(data.as_bytes()).collect();
+        assert_eq!(txns.len(), 3);
+        let csv = read("type,client,tx,amount\ndeposit,1,1,2.5\ndispute,1,1\n");
+        assert_eq!(txns[0].as_ref().unwrap(), csv[0].as_ref().unwrap());
+        assert_eq!(txns[1].as_ref().unwrap(), csv[1].as_ref().unwrap());
+        assert!(matches!(txns[2], Err(HelaError::JsonError(4, _))));
+    }
+
+    #[test]
+    fn detects_input_format() -> Fallible<()> {
+        let mut input_file = tempfile::NamedTempFile::new()?;
+        std::io::Write::write_all(&mut input_file, b"\n  {\"type\": \"deposit\"}")?;
+        let fname = input_file.path().to_str().unwrap();
+        assert_eq!(InputFormat::detect(fname)?, InputFormat::JsonLines);
+        let mut array_file = tempfile::Builder::new().suffix(".json").tempfile()?;
+        std::io::Write::write_all(&mut array_file, b"[{\"type\": \"deposit\"}]")?;
+        assert!(matches!(
+            InputFormat::detect(array_file.path().to_str().unwrap()),
+            Err(HelaError::UnsupportedInputFormat(..))
+        ));
+        assert_eq!(InputFormat::detect("transactions.csv")?, InputFormat::Csv);
+        assert_eq!(
+            InputFormat::detect("transactions.jsonl")?,
+            InputFormat::JsonLines
+        );
         Ok(())
     }
+
+    #[test]
+    fn maps_columns_by_header() {
+        let txns = read("client,tx,amount,type\n1,2,3.5,deposit\n");
+        let txn = txns[0].clone().unwrap();
+        assert_eq!(txn.r#type, TransactionType::Deposit);
+        assert_eq!((txn.client, txn.id, txn.amount), (1, 2, Some(3.5)));
+    }
 }
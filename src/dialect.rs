@@ -0,0 +1,264 @@
+//! CSV dialects and column mappings of transaction files.
+//!
+//! Dialects are loaded from a TOML file, where every setting is optional:
+//!
+//! ```toml
+//! delimiter = ";"
+//! decimal_separator = ","
+//!
+//! # header names of the columns
+//! [columns]
+//! txn_type = "type"
+//! client_id = "client"
+//!
+//! # values of the type column
+//! [types]
+//! DEP = "deposit"
+//! WDR = "withdrawal"
+//! ```
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::data::{parse_optional, parse_type};
+use crate::{Amount, Fallible, HelaError, TransactionType};
+
+/// Columns of a transactions file, in the order of headerless files
+pub(crate) const COLUMNS: [&str; 6] =
+    ["type", "client", "tx", "amount", "timestamp", "destination"];
+
+/// CSV dialect and column mapping of a transactions file
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CsvDialect {
+    /// Field delimiter
+    pub delimiter: char,
+    /// Whether fields may be quoted
+    pub quoting: bool,
+    /// Quote character
+    pub quote: char,
+    /// Whether the first row names the columns, otherwise columns are in the order
+    /// `type,client,tx,amount,timestamp,destination`
+    pub headers: bool,
+    /// Decimal separator of amounts, which must not group thousands
+    pub decimal_separator: char,
+    /// Column named by each header that differs from the column name, e.g. `client_id = "client"`
+    pub columns: HashMap<String, String>,
+    /// Transaction type of each type value that differs from the type name, e.g. `DEP = "deposit"`
+    pub types: HashMap<String, TransactionType>,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        CsvDialect {
+            delimiter: ',',
+            quoting: true,
+            quote: '"',
+            headers: true,
+            decimal_separator: '.',
+            columns: HashMap::new(),
+            types: HashMap::new(),
+        }
+    }
+}
+
+impl CsvDialect {
+    /// Load a dialect from a TOML configuration file
+    pub fn from_path(fname: &str) -> Fallible<CsvDialect> {
+        let config = std::fs::read_to_string(fname)?;
+        let dialect: CsvDialect = toml::from_str(&config).map_err(HelaError::ConfigError)?;
+        dialect.validate()?;
+        Ok(dialect)
+    }
+
+    /// Check that the separators are distinct single byte characters and columns are mapped to
+    /// known columns
+    pub fn validate(&self) -> Fallible<()> {
+        let separators = [
+            ("delimiter", self.delimiter),
+            ("quote", self.quote),
+            ("decimal separator", self.decimal_separator),
+        ];
+        for (idx, &(name, separator)) in separators.iter().enumerate() {
+            if !separator.is_ascii() || separator == '\n' || separator == '\r' {
+                return Err(HelaError::DialectError(format!(
+                    "{} {:?} is not a single byte character",
+                    name, separator
+                )));
+            }
+            if let Some((other, _)) = separators[..idx].iter().find(|(_, c)| *c == separator) {
+                return Err(HelaError::DialectError(format!(
+                    "{} and {} are both {:?}",
+                    other, name, separator
+                )));
+            }
+        }
+        if let Some(column) = self
+            .columns
+            .values()
+            .find(|column| !COLUMNS.contains(&column.as_str()))
+        {
+            return Err(HelaError::DialectError(format!(
+                "unknown column '{}'",
+                column
+            )));
+        }
+        Ok(())
+    }
+
+    /// Builder of CSV readers of this dialect
+    pub(crate) fn reader_builder(&self) -> csv::ReaderBuilder {
+        let mut builder = csv::ReaderBuilder::new();
+        // fields are trimmed as they are parsed, the reader's trimming allocates a record per row
+        builder
+            .flexible(true)
+            .trim(csv::Trim::Headers)
+            .delimiter(self.delimiter as u8)
+            .quoting(self.quoting)
+            .quote(self.quote as u8)
+            .has_headers(self.headers);
+        builder
+    }
+
+    /// Quote byte, if fields may be quoted
+    pub(crate) fn quote_byte(&self) -> Option<u8> {
+        Some(self.quote as u8).filter(|_| self.quoting)
+    }
+
+    /// Whether values need no mapping before they are parsed
+    fn is_plain(&self) -> bool {
+        self.types.is_empty() && self.decimal_separator == '.'
+    }
+
+    /// Column named by a header
+    pub(crate) fn column<'a>(&'a self, header: &'a [u8]) -> &'a [u8] {
+        std::str::from_utf8(header)
+            .ok()
+            .and_then(|header| self.columns.get(header))
+            .map_or(header, |column| column.as_bytes())
+    }
+
+    /// Parse a type value, either aliased or named
+    pub(crate) fn parse_type(&self, field: &[u8]) -> Option<TransactionType> {
+        if !self.types.is_empty() {
+            let alias = std::str::from_utf8(field)
+                .ok()
+                .and_then(|field| self.types.get(field));
+            if let Some(r#type) = alias {
+                return Some(*r#type);
+            }
+        }
+        parse_type(field)
+    }
+
+    /// Parse an optional amount with the decimal separator
+    pub(crate) fn parse_amount(&self, field: &[u8]) -> Option<Option<Amount>> {
+        let separator = self.decimal_separator as u8;
+        if separator == b'.' || !field.contains(&separator) {
+            return parse_optional(field);
+        }
+        let mut buf = [0; 64];
+        let buf = buf.get_mut(..field.len())?;
+        for (to, &from) in buf.iter_mut().zip(field) {
+            *to = if from == separator { b'.' } else { from };
+        }
+        parse_optional(buf)
+    }
+
+    /// Map the type and amount of a trimmed record in column order to their plain values,
+    /// for serde to parse
+    pub(crate) fn normalize(&self, rec: &mut csv::StringRecord) {
+        if self.is_plain() {
+            return;
+        }
+        let separator = self.decimal_separator.to_string();
+        let normalized: csv::StringRecord = rec
+            .iter()
+            .enumerate()
+            .map(|(idx, field)| match idx {
+                0 => self
+                    .types
+                    .get(field)
+                    .map_or(field.into(), |r#type| r#type.name().into()),
+                3 => field.replace(&separator, "."),
+                _ => field.to_string(),
+            })
+            .collect();
+        let position = rec.position().cloned();
+        *rec = normalized;
+        rec.set_position(position);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CsvDataReader;
+
+    #[test]
+    fn reads_dialect() -> Fallible<()> {
+        let dialect: CsvDialect = toml::from_str(
+            r#"
+            delimiter = ";"
+            decimal_separator = ","
+            [columns]
+            txn_type = "type"
+            client_id = "client"
+            [types]
+            DEP = "deposit"
+            "#,
+        )
+        .map_err(HelaError::ConfigError)?;
+        dialect.validate()?;
+        let data = "txn_type;client_id;tx;amount\nDEP;1;1;2,5\nwithdrawal;1;2;\"1,25\"\n";
+        let txns = CsvDataReader::from_reader_with_dialect(data.as_bytes(), &dialect)
+            .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(txns.len(), 2);
+        assert_eq!(txns[0].r#type, TransactionType::Deposit);
+        assert_eq!((txns[0].client, txns[0].amount), (1, Some(2.5)));
+        assert_eq!(txns[1].amount, Some(1.25));
+
+        // an unknown column falls back to reading by position, with the same mapping
+        let data = "kind;client;tx;amount\nDEP;1;1;2,5\nDEP;1;2;x\n";
+        let txns: Vec<_> =
+            CsvDataReader::from_reader_with_dialect(data.as_bytes(), &dialect).collect();
+        assert_eq!(txns[0].as_ref().unwrap().amount, Some(2.5));
+        assert!(txns[1].is_err());
+
+        let dialect = CsvDialect {
+            headers: false,
+            ..Default::default()
+        };
+        let txns = CsvDataReader::from_reader_with_dialect(&b"deposit,1,1,2.5\n"[..], &dialect)
+            .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(txns[0].amount, Some(2.5));
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_dialect() {
+        let dialect = CsvDialect {
+            columns: vec![("id".to_string(), "txid".to_string())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            dialect.validate(),
+            Err(HelaError::DialectError(_))
+        ));
+        let dialect = CsvDialect {
+            delimiter: '§',
+            ..Default::default()
+        };
+        assert!(dialect.validate().is_err());
+        let dialect = CsvDialect {
+            decimal_separator: ',',
+            ..Default::default()
+        };
+        assert!(matches!(
+            dialect.validate(),
+            Err(HelaError::DialectError(e)) if e == "delimiter and decimal separator are both ','"
+        ));
+    }
+}
@@ -0,0 +1,211 @@
+//! Differences between two sets of accounts, e.g. the outputs of consecutive runs.
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{ser_float, Account, Amount, Client, Fallible, HelaError, AMOUNT_EPSILON};
+
+/// Change of a client's account between two sets of accounts
+#[derive(Copy, Clone, Debug, Serialize, PartialEq)]
+pub struct AccountDelta {
+    /// Client
+    pub client: Client,
+    /// Change of the available amount
+    #[serde(serialize_with = "ser_float")]
+    pub available: Amount,
+    /// Change of the held amount
+    #[serde(serialize_with = "ser_float")]
+    pub held: Amount,
+    /// Change of the total amount
+    #[serde(serialize_with = "ser_float")]
+    pub total: Amount,
+    /// Locked status before
+    pub locked_before: bool,
+    /// Locked status after
+    pub locked_after: bool,
+}
+
+/// Money movement over all accounts
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DiffSummary {
+    /// Number of accounts that changed
+    pub changed: usize,
+    /// Sum of the totals that increased
+    pub inflow: Amount,
+    /// Sum of the totals that decreased, as a positive amount
+    pub outflow: Amount,
+    /// Change of the held amounts
+    pub held: Amount,
+    /// Number of accounts that were locked
+    pub locked: usize,
+    /// Number of accounts that were unlocked
+    pub unlocked: usize,
+}
+
+impl DiffSummary {
+    /// Change of the total amounts
+    pub fn net(&self) -> Amount {
+        self.inflow - self.outflow
+    }
+}
+
+impl fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} accounts changed: {:.2} in, {:.2} out, {:.2} net, {:.2} held, {} locked, {} unlocked",
+            self.changed,
+            self.inflow,
+            self.outflow,
+            self.net(),
+            self.held,
+            self.locked,
+            self.unlocked
+        )
+    }
+}
+
+/// Changed accounts, in client order, and their summary
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccountDiff {
+    /// Changes of the accounts that changed
+    pub deltas: Vec<AccountDelta>,
+    /// Money movement over all accounts
+    pub summary: DiffSummary,
+}
+
+fn by_client(accounts: impl IntoIterator<Item = Account>) -> Fallible<BTreeMap<Client, Account>> {
+    let mut by_client = BTreeMap::new();
+    for acc in accounts {
+        if by_client.insert(acc.client, acc).is_some() {
+            return Err(HelaError::DuplicateAccount(acc.client));
+        }
+    }
+    Ok(by_client)
+}
+
+/// Difference of two amounts, zero if they only differ by rounding errors
+fn delta(before: Amount, after: Amount) -> Amount {
+    let delta = after - before;
+    if delta.abs() <= AMOUNT_EPSILON * before.abs().max(after.abs()).max(1.0) {
+        0.0
+    } else {
+        delta
+    }
+}
+
+/// Compare two sets of accounts. A client missing from either set counts as an empty,
+/// unlocked account there.
+pub fn diff_accounts(
+    before: impl IntoIterator<Item = Account>,
+    after: impl IntoIterator<Item = Account>,
+) -> Fallible<AccountDiff> {
+    let before = by_client(before)?;
+    let after = by_client(after)?;
+    let mut clients: Vec<Client> = before.keys().chain(after.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+
+    let mut diff = AccountDiff::default();
+    for client in clients {
+        let empty = Account {
+            client,
+            ..Default::default()
+        };
+        let old = before.get(&client).copied().unwrap_or(empty);
+        let new = after.get(&client).copied().unwrap_or(empty);
+        let change = AccountDelta {
+            client,
+            available: delta(old.available, new.available),
+            held: delta(old.held, new.held),
+            total: delta(old.total, new.total),
+            locked_before: old.locked,
+            locked_after: new.locked,
+        };
+        let locked_changed = change.locked_before != change.locked_after;
+        if change.available == 0.0 && change.held == 0.0 && change.total == 0.0 && !locked_changed {
+            continue;
+        }
+        let summary = &mut diff.summary;
+        summary.changed += 1;
+        if change.total > 0.0 {
+            summary.inflow += change.total;
+        } else {
+            summary.outflow -= change.total;
+        }
+        summary.held += change.held;
+        if locked_changed && change.locked_after {
+            summary.locked += 1;
+        } else if locked_changed {
+            summary.unlocked += 1;
+        }
+        diff.deltas.push(change);
+    }
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn account(client: Client, available: Amount, held: Amount, locked: bool) -> Account {
+        Account {
+            client,
+            available,
+            held,
+            total: available + held,
+            locked,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn diffs_accounts() -> Fallible<()> {
+        let before = vec![
+            account(1, 1.0, 0.0, false),
+            account(2, 5.0, 1.0, false),
+            account(3, 0.1 + 0.2, 0.0, false),
+            account(4, 2.0, 0.0, false),
+        ];
+        let after = vec![
+            account(1, 1.0, 0.0, false),
+            account(2, 3.0, 0.0, true),
+            account(3, 0.3, 0.0, false),
+            account(5, 4.5, 0.5, false),
+        ];
+        let diff = diff_accounts(before, after)?;
+        let clients: Vec<_> = diff.deltas.iter().map(|delta| delta.client).collect();
+        assert_eq!(clients, vec![2, 4, 5]);
+        assert_eq!(
+            diff.deltas[0],
+            AccountDelta {
+                client: 2,
+                available: -2.0,
+                held: -1.0,
+                total: -3.0,
+                locked_before: false,
+                locked_after: true,
+            }
+        );
+        assert_eq!(
+            diff.summary,
+            DiffSummary {
+                changed: 3,
+                inflow: 5.0,
+                outflow: 5.0,
+                held: -0.5,
+                locked: 1,
+                unlocked: 0,
+            }
+        );
+        assert_eq!(diff.summary.net(), 0.0);
+
+        let duplicate = vec![account(1, 1.0, 0.0, false), account(1, 2.0, 0.0, false)];
+        assert!(matches!(
+            diff_accounts(duplicate, vec![]),
+            Err(HelaError::DuplicateAccount(1))
+        ));
+        Ok(())
+    }
+}
@@ -1,23 +1,242 @@
-use crate::{Account, Fallible, HelaError, Storage, Transaction, TransactionType};
+use std::collections::HashMap;
+
+use tracing::{debug, error, info, info_span, warn};
+
+use crate::{
+    Account, AdminTransaction, Alert, Amount, Client, ClientActivity, Fallible, FraudDetector,
+    HelaError, IgnoreReason, MemoryUsage, Rejection, RunStats, Storage, Timestamp, Transaction,
+    TransactionId, TransactionType, AMOUNT_EPSILON, MAX_AMOUNT,
+};
 
 /// Payments transcations processor
 pub struct PaymentEngine {
     store: Box<dyn Storage>,
+    fraud_detector: Option<FraudDetector>,
+    alerts: Vec<Alert>,
+    /// Client activity and number of alerts before the current unit of work observed a
+    /// transaction, to restore on rollback
+    fraud_journal: Option<(Client, Option<ClientActivity>, usize)>,
+    audit_trail: Vec<AdminTransaction>,
+    rejections: Vec<Rejection>,
+    dispute_window: Option<Timestamp>,
+    chargeback_window: Option<Timestamp>,
+    last_timestamps: HashMap<Client, Timestamp>,
+    stats: RunStats,
+}
+
+/// What applying a transaction did
+#[derive(Copy, Clone)]
+enum Outcome {
+    /// Applied, acting on the amount
+    Applied(Amount),
+    /// Ignored without changing any account
+    Ignored(IgnoreReason),
 }
 
 impl PaymentEngine {
     /// create a new engine
     pub fn new(store: Box<dyn Storage>) -> PaymentEngine {
-        PaymentEngine { store }
+        PaymentEngine {
+            store,
+            fraud_detector: None,
+            alerts: vec![],
+            fraud_journal: None,
+            audit_trail: vec![],
+            rejections: vec![],
+            dispute_window: None,
+            chargeback_window: None,
+            last_timestamps: HashMap::new(),
+            stats: RunStats::default(),
+        }
+    }
+
+    /// Reject disputes raised more than `window` seconds after the disputed transaction
+    pub fn with_dispute_window(mut self, window: Timestamp) -> PaymentEngine {
+        self.dispute_window = Some(window);
+        self
+    }
+
+    /// Reject chargebacks more than `window` seconds after the dispute they act on
+    pub fn with_chargeback_window(mut self, window: Timestamp) -> PaymentEngine {
+        self.chargeback_window = Some(window);
+        self
+    }
+
+    /// Evaluate fraud rules after each applied transaction, locking accounts that trigger them
+    pub fn with_fraud_detector(mut self, detector: FraudDetector) -> PaymentEngine {
+        self.fraud_detector = Some(detector);
+        self
     }
 
     /// Execute a single transcation
+    ///
+    /// The transaction is applied as a single storage unit of work,
+    /// so if any of its steps fail none of its changes are kept.
     pub fn execute_transaction(&mut self, txn: Transaction) -> Fallible<()> {
-        match txn.r#type {
+        let _span = info_span!(
+            "transaction",
+            "type" = txn.r#type.name(),
+            client = txn.client,
+            tx = txn.id
+        )
+        .entered();
+        let res = self.execute(txn);
+        match &res {
+            Ok(()) => {}
+            Err(e) if e.is_rejection() => warn!(error = %e, "transaction rejected"),
+            Err(e) => error!(error = %e, "transaction failed"),
+        }
+        res
+    }
+
+    fn execute(&mut self, txn: Transaction) -> Fallible<()> {
+        *self
+            .stats
+            .transactions
+            .entry(txn.r#type.name())
+            .or_default() += 1;
+        Self::check_amount(&txn)?;
+        self.check_timestamp(&txn)?;
+        self.store.begin()?;
+        // only applied transactions are activity the fraud rules should see
+        let res = self
+            .apply_transaction(txn)
+            .and_then(|outcome| match outcome {
+                Outcome::Applied(_) => self.detect_fraud(&txn).map(|_| outcome),
+                Outcome::Ignored(_) => Ok(outcome),
+            });
+        let outcome = self.finish_unit_of_work(res)?;
+        if let Some(timestamp) = txn.timestamp {
+            self.last_timestamps.insert(txn.client, timestamp);
+        }
+        match outcome {
+            Outcome::Applied(amount) => debug!(amount, "transaction applied"),
+            Outcome::Ignored(reason) => info!(reason = reason.name(), "transaction ignored"),
+        }
+        match (outcome, txn.r#type) {
+            (Outcome::Ignored(reason), _) => {
+                *self.stats.ignored.entry(reason.name()).or_default() += 1
+            }
+            (Outcome::Applied(amount), TransactionType::Deposit) => self.stats.deposited += amount,
+            (Outcome::Applied(amount), TransactionType::Withdrawal) => {
+                self.stats.withdrawn += amount
+            }
+            (Outcome::Applied(amount), TransactionType::Chargeback) => {
+                self.stats.charged_back += amount
+            }
+            (Outcome::Applied(_), _) => {}
+        }
+        Ok(())
+    }
+
+    /// Commit the storage unit of work if all its steps succeeded, roll it back otherwise
+    fn finish_unit_of_work<T>(&mut self, res: Fallible<T>) -> Fallible<T> {
+        let fraud_journal = self.fraud_journal.take();
+        let res = match res {
+            Ok(outcome) => self.store.commit().map(|_| outcome),
+            Err(e) => {
+                debug!("rolling back unit of work");
+                self.store.rollback()?;
+                Err(e)
+            }
+        };
+        if let (Err(_), Some((client, activity, alerts))) = (&res, fraud_journal) {
+            if let Some(detector) = self.fraud_detector.as_mut() {
+                detector.restore(client, activity);
+            }
+            self.alerts.truncate(alerts);
+        }
+        res
+    }
+
+    /// Amounts must be present on deposits and withdrawals, and be sensible wherever given
+    fn check_amount(txn: &Transaction) -> Fallible<()> {
+        match txn.amount {
+            Some(amount) if !(0.0..=MAX_AMOUNT).contains(&amount) => {
+                Err(HelaError::InvalidAmount(txn.id))
+            }
+            None if matches!(
+                txn.r#type,
+                TransactionType::Deposit | TransactionType::Withdrawal
+            ) =>
+            {
+                Err(HelaError::MissingAmount(txn.id))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Timestamps of a client's transactions must be non-decreasing
+    fn check_timestamp(&self, txn: &Transaction) -> Fallible<()> {
+        match (txn.timestamp, self.last_timestamps.get(&txn.client)) {
+            (Some(timestamp), Some(&last)) if timestamp < last => {
+                Err(HelaError::OutOfOrderTimestamp(txn.id))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Amount a dispute, resolve or chargeback acts on, at most `limit`
+    fn dispute_amount(txn: &Transaction, limit: Amount) -> Fallible<Amount> {
+        let amount = txn.amount.unwrap_or(limit);
+        if amount <= 0.0 || amount > limit + AMOUNT_EPSILON {
+            return Err(HelaError::InvalidDisputeAmount(txn.id));
+        }
+        Ok(amount.min(limit))
+    }
+
+    /// Transactions without timestamps are never outside a window
+    fn within_window(
+        window: Option<Timestamp>,
+        since: Option<Timestamp>,
+        at: Option<Timestamp>,
+    ) -> bool {
+        match (window, since, at) {
+            (Some(window), Some(since), Some(at)) => at.saturating_sub(since) <= window,
+            _ => true,
+        }
+    }
+
+    fn detect_fraud(&mut self, txn: &Transaction) -> Fallible<()> {
+        let alerts = match self.fraud_detector.as_mut() {
+            Some(detector) => {
+                let activity = detector.activity(txn.client).cloned();
+                self.fraud_journal = Some((txn.client, activity, self.alerts.len()));
+                detector.observe(txn)
+            }
+            None => return Ok(()),
+        };
+        if !alerts.is_empty() && !self.store.is_locked(txn.client).unwrap_or(false) {
+            for alert in alerts.iter() {
+                warn!(rule = %alert.rule, "fraud rule triggered, locking account");
+            }
+            self.store.lock_account(txn.client)?;
+            self.alerts.extend(alerts);
+        }
+        Ok(())
+    }
+
+    /// Locked and closed accounts take no deposits and pay nothing out
+    fn check_unlocked(&self, client: Client) -> Fallible<()> {
+        if self.store.is_locked(client).unwrap_or(false) {
+            return Err(HelaError::AccountLocked(client));
+        }
+        Ok(())
+    }
+
+    fn apply_transaction(&mut self, txn: Transaction) -> Fallible<Outcome> {
+        if matches!(
+            txn.r#type,
+            TransactionType::Deposit | TransactionType::Withdrawal | TransactionType::Refund
+        ) {
+            self.check_unlocked(txn.client)?;
+        }
+        let amount = match txn.r#type {
             TransactionType::Deposit => {
-                debug_assert!(txn.amount.is_some());
-                self.store.deposit(txn.client, txn.amount.unwrap())?;
+                let amount = txn.amount.unwrap();
+                self.store.deposit(txn.client, amount)?;
                 self.store.store_transaction(txn)?;
+                amount
             }
             // Spec: If a client does not have sufficient available funds the withdrawal
             // should fail and the total amount of funds should not change.
@@ -27,14 +246,17 @@ impl PaymentEngine {
             // Assumption is the account state doesn't change.
             // The engine suppresses the InsufficientAccountFunds error.
             TransactionType::Withdrawal => {
-                debug_assert!(txn.amount.is_some());
-                match self.store.withdraw(txn.client, txn.amount.unwrap()) {
-                    Err(HelaError::InsufficientAccountFunds(_)) => {}
+                let amount = txn.amount.unwrap();
+                match self.store.withdraw(txn.client, amount) {
+                    Err(HelaError::InsufficientAccountFunds(_)) => {
+                        return Ok(Outcome::Ignored(IgnoreReason::InsufficientFunds))
+                    }
                     Err(e) => return Err(e),
                     Ok(_) => {
                         self.store.store_transaction(txn)?;
                     }
                 }
+                amount
             }
             TransactionType::Chargeback => {
                 //  Spec: if the tx specified doesn't exist, or the tx isn't under dispute,
@@ -42,39 +264,98 @@ impl PaymentEngine {
                 //
                 //  How do you know tx is under dispute?
                 //
-                debug_assert!(txn.amount.is_none());
-                if let Ok(prev_txn) = self.store.get_transaction(txn.id) {
-                    debug_assert!(prev_txn.client == txn.client);
-                    debug_assert!(prev_txn.amount.is_some());
-                    if prev_txn.disputed {
-                        self.store
-                            .chargeback(txn.client, prev_txn.amount.unwrap())?;
-                    }
+                //  A chargeback without an amount charges back everything under dispute.
+                let prev_txn = match self.store.get_transaction(txn.id) {
+                    Ok(prev_txn) => prev_txn,
+                    Err(_) => return Ok(Outcome::Ignored(IgnoreReason::UnknownTransaction)),
+                };
+                if prev_txn.client != txn.client {
+                    return Err(HelaError::ClientMismatch(txn.id));
+                }
+                if prev_txn.disputed <= 0.0 {
+                    return Ok(Outcome::Ignored(IgnoreReason::NotDisputed));
                 }
+                if !Self::within_window(self.chargeback_window, prev_txn.disputed_at, txn.timestamp)
+                {
+                    return Err(HelaError::ChargebackWindowExpired(txn.id));
+                }
+                let amount = Self::dispute_amount(&txn, prev_txn.disputed)?;
+                self.store.chargeback(txn.client, amount)?;
+                self.store
+                    .mark_transaction_as_charged_back(prev_txn.id, amount)?;
+                amount
             }
             TransactionType::Dispute => {
-                debug_assert!(txn.amount.is_none());
-                if let Ok(prev_txn) = self.store.get_transaction(txn.id) {
-                    debug_assert!(prev_txn.client == txn.client);
-                    debug_assert!(prev_txn.amount.is_some());
-                    self.store.dispute(txn.client, prev_txn.amount.unwrap())?;
-                    self.store.mark_transaction_as_disputed(prev_txn.id)?;
+                // A dispute without an amount disputes everything that can still be disputed.
+                let prev_txn = match self.store.get_transaction(txn.id) {
+                    Ok(prev_txn) => prev_txn,
+                    Err(_) => return Ok(Outcome::Ignored(IgnoreReason::UnknownTransaction)),
+                };
+                if prev_txn.client != txn.client {
+                    return Err(HelaError::ClientMismatch(txn.id));
+                }
+                if !Self::within_window(self.dispute_window, prev_txn.timestamp, txn.timestamp) {
+                    return Err(HelaError::DisputeWindowExpired(txn.id));
                 }
+                let amount = Self::dispute_amount(&txn, prev_txn.disputable_amount())?;
+                self.store.dispute(txn.client, amount)?;
+                self.store
+                    .mark_transaction_as_disputed(prev_txn.id, amount, txn.timestamp)?;
+                amount
             }
             TransactionType::Resolve => {
-                debug_assert!(txn.amount.is_none());
-                if let Ok(prev_txn) = self.store.get_transaction(txn.id) {
-                    debug_assert!(prev_txn.client == txn.client);
-                    debug_assert!(prev_txn.amount.is_some());
-                    if prev_txn.disputed {
-                        self.store.resolve(txn.client, prev_txn.amount.unwrap())?;
-                        self.store.mark_transaction_as_undisputed(prev_txn.id)?;
-                    }
+                // A resolve without an amount resolves everything under dispute.
+                let prev_txn = match self.store.get_transaction(txn.id) {
+                    Ok(prev_txn) => prev_txn,
+                    Err(_) => return Ok(Outcome::Ignored(IgnoreReason::UnknownTransaction)),
+                };
+                if prev_txn.client != txn.client {
+                    return Err(HelaError::ClientMismatch(txn.id));
+                }
+                if prev_txn.disputed <= 0.0 {
+                    return Ok(Outcome::Ignored(IgnoreReason::NotDisputed));
                 }
+                let amount = Self::dispute_amount(&txn, prev_txn.disputed)?;
+                self.store.resolve(txn.client, amount)?;
+                self.store
+                    .mark_transaction_as_undisputed(prev_txn.id, amount)?;
+                amount
             }
-        }
+            // A refund returns part or all of a deposit to the client, and that part
+            // can no longer be disputed.
+            TransactionType::Refund => {
+                let prev_txn = self
+                    .store
+                    .get_transaction(txn.id)
+                    .map_err(|_| HelaError::InvalidRefund(txn.id))?;
+                let amount = txn.amount.ok_or(HelaError::InvalidRefund(txn.id))?;
+                if prev_txn.r#type != TransactionType::Deposit
+                    || prev_txn.client != txn.client
+                    || amount <= 0.0
+                    || amount > prev_txn.disputable_amount() + AMOUNT_EPSILON
+                {
+                    return Err(HelaError::InvalidRefund(txn.id));
+                }
+                self.store.withdraw(txn.client, amount)?;
+                self.store
+                    .mark_transaction_as_refunded(prev_txn.id, amount)?;
+                amount
+            }
+            TransactionType::Transfer => {
+                let amount = txn.amount.ok_or(HelaError::InvalidTransfer(txn.id))?;
+                let destination = txn.destination.ok_or(HelaError::InvalidTransfer(txn.id))?;
+                if destination == txn.client || amount <= 0.0 {
+                    return Err(HelaError::InvalidTransfer(txn.id));
+                }
+                self.store.transfer(txn.client, destination, amount)?;
+                amount
+            }
+            TransactionType::Unlock | TransactionType::Adjust | TransactionType::Close => {
+                return Err(HelaError::UnauthorisedTransaction(txn.id));
+            }
+        };
 
-        Ok(())
+        Ok(Outcome::Applied(amount))
     }
 
     /// Execute a stream of transcations
@@ -83,13 +364,433 @@ impl PaymentEngine {
         I: Iterator<Item = Fallible<Transaction>>,
     {
         for txn in txns {
-            self.execute_transaction(txn?)?;
+            let txn = txn?;
+            if let Err(e) = self.execute_transaction(txn) {
+                match e.rejection_reason() {
+                    Some(reason) => self.reject(txn.client, txn.id, reason, &e),
+                    None => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute a single administrative transaction, recording it in the audit trail
+    pub fn execute_admin_transaction(&mut self, mut txn: AdminTransaction) -> Fallible<()> {
+        self.store.begin()?;
+        let res = self.apply_admin_transaction(&mut txn);
+        self.finish_unit_of_work(res)?;
+        self.audit_trail.push(txn);
+        Ok(())
+    }
+
+    fn apply_admin_transaction(&mut self, txn: &mut AdminTransaction) -> Fallible<()> {
+        match txn.r#type {
+            TransactionType::Unlock => self.store.unlock_account(txn.client)?,
+            TransactionType::Adjust => {
+                let amount = txn
+                    .amount
+                    .filter(|amount| amount.abs() <= MAX_AMOUNT)
+                    .ok_or(HelaError::InvalidAdminTransaction(txn.id))?;
+                self.store.adjust(txn.client, amount)?;
+            }
+            // Closing an account with funds under dispute would lose track of them,
+            // so those disputes have to be resolved or charged back first. A debt on the
+            // account's credit line cannot be paid out, it has to be settled by an adjustment.
+            TransactionType::Close => {
+                let acc = self.store.get_account(txn.client)?;
+                if acc.held != 0.0 {
+                    return Err(HelaError::HeldFunds(txn.client));
+                }
+                if acc.available < 0.0 {
+                    return Err(HelaError::NegativeBalance(txn.client));
+                }
+                self.store.adjust(txn.client, -acc.available)?;
+                self.store.lock_account(txn.client)?;
+                txn.amount = Some(acc.available);
+            }
+            _ => return Err(HelaError::InvalidAdminTransaction(txn.id)),
+        }
+        Ok(())
+    }
+
+    /// Execute a stream of administrative transcations. Transactions that cannot be applied,
+    /// e.g. on an unknown account, are rejected and left out of the audit trail.
+    pub fn execute_admin_transactions<I>(&mut self, txns: I) -> Fallible<()>
+    where
+        I: Iterator<Item = Fallible<AdminTransaction>>,
+    {
+        for txn in txns {
+            let txn = txn?;
+            let (client, tx) = (txn.client, txn.id);
+            if let Err(e) = self.execute_admin_transaction(txn) {
+                let reason = match (&e, e.rejection_reason()) {
+                    (HelaError::AccountNotFound(_), _) => "account_not_found",
+                    (_, Some(reason)) => reason,
+                    (_, None) => return Err(e),
+                };
+                warn!(client, tx, reason, "administrative transaction rejected");
+                self.reject(client, tx, reason, &e);
+            }
         }
         Ok(())
     }
 
+    /// Record a rejected transaction
+    fn reject(&mut self, client: Client, tx: TransactionId, reason: &'static str, e: &HelaError) {
+        *self.stats.rejected.entry(reason).or_default() += 1;
+        self.rejections.push(Rejection {
+            client,
+            tx,
+            reason: e.to_string(),
+        });
+    }
+
+    /// Administrative transactions applied, with the amount paid out on closures
+    pub fn audit_trail(&self) -> &[AdminTransaction] {
+        &self.audit_trail
+    }
+
+    /// Statistics of the transactions executed, with the held amounts and locked status of
+    /// the accounts
+    pub fn stats(&self) -> Fallible<RunStats> {
+        Ok(self.stats.clone().with_accounts(self.store.get_accounts()?))
+    }
+
+    /// Transactions rejected while executing a stream of transactions
+    pub fn rejections(&self) -> &[Rejection] {
+        &self.rejections
+    }
+
+    /// Alerts raised by the fraud rules, in the order they were raised
+    pub fn alerts(&self) -> &[Alert] {
+        &self.alerts
+    }
+
+    /// Memory used by the storage backend, if it can be measured
+    pub fn memory_usage(&self) -> Option<MemoryUsage> {
+        self.store.memory_usage()
+    }
+
     /// Get a stream if accounts from the storage backend
     pub fn accounts(&self) -> Fallible<Box<dyn Iterator<Item = Account> + '_>> {
         self.store.get_accounts()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    //! Property tests comparing the engine against a trivially correct reference model.
+    //!
+    //! Amounts are whole quarters so that every floating point sum the engine computes
+    //! is exact, and the model can use integer arithmetic.
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::{InMemoryStore, TransactionId};
+
+    const QUARTER: Amount = 0.25;
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Deposit(Client, i64),
+        Withdrawal(Client, i64),
+        Dispute(usize, Option<i64>),
+        Resolve(usize, Option<i64>),
+        Chargeback(usize, Option<i64>),
+        Refund(usize, i64),
+        Transfer(Client, Client, i64),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let client = 1..4u16;
+        let quarters = 1..400i64;
+        // Transactions are referenced by index among those created so far,
+        // with out of range indexes referencing unknown transactions.
+        let target = 0..40usize;
+        prop_oneof![
+            3 => (client.clone(), quarters.clone()).prop_map(|(c, q)| Op::Deposit(c, q)),
+            2 => (client.clone(), quarters.clone()).prop_map(|(c, q)| Op::Withdrawal(c, q)),
+            2 => (target.clone(), proptest::option::of(quarters.clone()))
+                .prop_map(|(t, q)| Op::Dispute(t, q)),
+            1 => (target.clone(), proptest::option::of(quarters.clone()))
+                .prop_map(|(t, q)| Op::Resolve(t, q)),
+            1 => (target.clone(), proptest::option::of(quarters.clone()))
+                .prop_map(|(t, q)| Op::Chargeback(t, q)),
+            1 => (target, quarters.clone()).prop_map(|(t, q)| Op::Refund(t, q)),
+            1 => (client.clone(), client, quarters).prop_map(|(s, d, q)| Op::Transfer(s, d, q)),
+        ]
+    }
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    struct ModelAccount {
+        available: i64,
+        held: i64,
+        locked: bool,
+    }
+
+    #[derive(Clone, Copy, Debug)]
+    struct ModelTransaction {
+        client: Client,
+        deposit: bool,
+        amount: i64,
+        disputed: i64,
+        charged_back: i64,
+        refunded: i64,
+    }
+
+    /// Reference model of the engine, in whole quarters
+    #[derive(Default)]
+    struct Model {
+        accounts: HashMap<Client, ModelAccount>,
+        transactions: HashMap<TransactionId, ModelTransaction>,
+        /// Net money that entered the system, deposits less withdrawals,
+        /// chargebacks and refunds
+        inflow: i64,
+    }
+
+    impl Model {
+        fn available(&self, client: Client) -> i64 {
+            self.accounts.get(&client).map_or(0, |acc| acc.available)
+        }
+
+        fn apply(&mut self, txn: &Transaction) {
+            let amount = txn.amount.map(|amount| (amount / QUARTER) as i64);
+            let client = txn.client;
+            match txn.r#type {
+                // locked accounts take no deposits, withdrawals or refunds
+                TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Refund
+                    if self.accounts.get(&client).is_some_and(|acc| acc.locked) => {}
+                TransactionType::Deposit => {
+                    let amount = amount.unwrap();
+                    self.accounts.entry(client).or_default().available += amount;
+                    self.record(txn, true, amount);
+                    self.inflow += amount;
+                }
+                // a failed withdrawal still opens the client's account
+                TransactionType::Withdrawal => {
+                    let amount = amount.unwrap();
+                    let acc = self.accounts.entry(client).or_default();
+                    if acc.available >= amount {
+                        acc.available -= amount;
+                        self.record(txn, false, amount);
+                        self.inflow -= amount;
+                    }
+                }
+                TransactionType::Dispute => {
+                    if let Some(prev) = self.transactions.get_mut(&txn.id) {
+                        let limit = prev.amount - prev.disputed - prev.charged_back - prev.refunded;
+                        let amount = amount.unwrap_or(limit);
+                        if amount > 0 && amount <= limit {
+                            prev.disputed += amount;
+                            let acc = self.accounts.entry(client).or_default();
+                            acc.available -= amount;
+                            acc.held += amount;
+                        }
+                    }
+                }
+                TransactionType::Resolve | TransactionType::Chargeback => {
+                    if let Some(prev) = self.transactions.get_mut(&txn.id) {
+                        let amount = amount.unwrap_or(prev.disputed);
+                        if prev.disputed > 0 && amount > 0 && amount <= prev.disputed {
+                            prev.disputed -= amount;
+                            let acc = self.accounts.entry(client).or_default();
+                            acc.held -= amount;
+                            if txn.r#type == TransactionType::Resolve {
+                                acc.available += amount;
+                            } else {
+                                prev.charged_back += amount;
+                                acc.locked = true;
+                                self.inflow -= amount;
+                            }
+                        }
+                    }
+                }
+                TransactionType::Refund => {
+                    let amount = amount.unwrap();
+                    let available = self.available(client);
+                    if let Some(prev) = self.transactions.get_mut(&txn.id) {
+                        let limit = prev.amount - prev.disputed - prev.charged_back - prev.refunded;
+                        if prev.deposit
+                            && prev.client == client
+                            && amount <= limit
+                            && available >= amount
+                        {
+                            prev.refunded += amount;
+                            self.accounts.entry(client).or_default().available -= amount;
+                            self.inflow -= amount;
+                        }
+                    }
+                }
+                TransactionType::Transfer => {
+                    let amount = amount.unwrap();
+                    let destination = txn.destination.unwrap();
+                    let locked = |c| self.accounts.get(&c).is_some_and(|acc| acc.locked);
+                    if destination != client
+                        && !locked(client)
+                        && !locked(destination)
+                        && self.accounts.contains_key(&client)
+                        && self.available(client) >= amount
+                    {
+                        self.accounts.entry(client).or_default().available -= amount;
+                        self.accounts.entry(destination).or_default().available += amount;
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        fn record(&mut self, txn: &Transaction, deposit: bool, amount: i64) {
+            self.transactions.insert(
+                txn.id,
+                ModelTransaction {
+                    client: txn.client,
+                    deposit,
+                    amount,
+                    disputed: 0,
+                    charged_back: 0,
+                    refunded: 0,
+                },
+            );
+        }
+    }
+
+    fn txn(
+        r#type: TransactionType,
+        client: Client,
+        id: TransactionId,
+        amount: Option<i64>,
+    ) -> Transaction {
+        Transaction {
+            r#type,
+            client,
+            id,
+            amount: amount.map(|quarters| quarters as Amount * QUARTER),
+            timestamp: None,
+            destination: None,
+            disputed: 0.0,
+            disputed_at: None,
+            charged_back: 0.0,
+            refunded: 0.0,
+        }
+    }
+
+    /// Turn generated operations into transactions, resolving references
+    /// to earlier transactions and the client that made them
+    fn transactions(ops: Vec<Op>) -> Vec<Transaction> {
+        let mut created: Vec<(TransactionId, Client)> = vec![];
+        let mut next_id = 1;
+        let referenced = |target: usize, created: &[(TransactionId, Client)]| {
+            created
+                .get(target)
+                .copied()
+                .unwrap_or((1000 + target as TransactionId, 1))
+        };
+        ops.into_iter()
+            .map(|op| match op {
+                Op::Deposit(client, q) | Op::Withdrawal(client, q) => {
+                    let r#type = if let Op::Deposit(..) = op {
+                        TransactionType::Deposit
+                    } else {
+                        TransactionType::Withdrawal
+                    };
+                    let id = next_id;
+                    next_id += 1;
+                    created.push((id, client));
+                    txn(r#type, client, id, Some(q))
+                }
+                Op::Dispute(target, q) => {
+                    let (id, client) = referenced(target, &created);
+                    txn(TransactionType::Dispute, client, id, q)
+                }
+                Op::Resolve(target, q) => {
+                    let (id, client) = referenced(target, &created);
+                    txn(TransactionType::Resolve, client, id, q)
+                }
+                Op::Chargeback(target, q) => {
+                    let (id, client) = referenced(target, &created);
+                    txn(TransactionType::Chargeback, client, id, q)
+                }
+                Op::Refund(target, q) => {
+                    let (id, client) = referenced(target, &created);
+                    txn(TransactionType::Refund, client, id, Some(q))
+                }
+                Op::Transfer(source, destination, q) => {
+                    let id = next_id;
+                    next_id += 1;
+                    Transaction {
+                        destination: Some(destination),
+                        ..txn(TransactionType::Transfer, source, id, Some(q))
+                    }
+                }
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn engine_matches_model(ops in proptest::collection::vec(op(), 1..80)) {
+            let mut engine = PaymentEngine::new(Box::new(InMemoryStore::new()));
+            let mut model = Model::default();
+            let mut charged_back = std::collections::HashSet::new();
+
+            for txn in transactions(ops) {
+                let res = engine.execute_transaction(txn);
+                prop_assert!(res.is_ok() || res.as_ref().unwrap_err().is_rejection());
+                model.apply(&txn);
+
+                let accounts: HashMap<_, _> =
+                    engine.accounts()?.map(|acc| (acc.client, acc)).collect();
+                prop_assert_eq!(accounts.len(), model.accounts.len());
+                let locked = accounts.get(&txn.client).is_some_and(|acc| acc.locked);
+                if txn.r#type == TransactionType::Chargeback && locked {
+                    charged_back.insert(txn.client);
+                }
+                let mut total = 0.0;
+                for (client, expected) in model.accounts.iter() {
+                    let acc = accounts[client];
+                    prop_assert_eq!(acc.available, expected.available as Amount * QUARTER);
+                    prop_assert_eq!(acc.held, expected.held as Amount * QUARTER);
+                    prop_assert_eq!(acc.locked, expected.locked);
+                    prop_assert_eq!(acc.total, acc.available + acc.held);
+                    prop_assert!(acc.held >= 0.0);
+                    // accounts stay locked once charged back
+                    prop_assert!(!charged_back.contains(client) || acc.locked);
+                    total += acc.total;
+                }
+                // no money is created: balances only change by what entered the system
+                prop_assert_eq!(total, model.inflow as Amount * QUARTER);
+            }
+        }
+    }
+
+    #[test]
+    fn counts_stats() -> Fallible<()> {
+        let data = "type,client,tx,amount\ndeposit,1,1,5.0\ndeposit,2,2,1.0\nwithdrawal,2,3,2.0\n\
+                    dispute,1,1,\nchargeback,1,1,\nresolve,1,9,\nresolve,2,2,\ndeposit,1,4,\n";
+        let mut engine = PaymentEngine::new(Box::new(InMemoryStore::new()));
+        engine.execute_transactions(crate::CsvDataReader::from_reader(data.as_bytes()))?;
+        let stats = engine.stats()?;
+        assert_eq!(stats.total(), 8);
+        assert_eq!(stats.transactions["deposit"], 3);
+        assert_eq!(
+            stats.ignored.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("insufficient_funds", 1),
+                ("not_disputed", 1),
+                ("unknown_transaction", 1)
+            ]
+        );
+        assert_eq!(stats.rejected["missing_amount"], 1);
+        assert_eq!(
+            (stats.deposited, stats.withdrawn, stats.charged_back),
+            (6.0, 0.0, 5.0)
+        );
+        assert_eq!((stats.held, stats.locked_accounts), (0.0, 1));
+        Ok(())
+    }
+}
@@ -1,6 +1,6 @@
 use thiserror::Error;
 
-use crate::{Client, TransactionId};
+use crate::{Amount, Client, TransactionId};
 
 /// A result wrapper around HelaError
 pub type Fallible<T> = Result<T, HelaError>;
@@ -12,6 +12,42 @@ pub enum HelaError {
     #[error("DataStore locking error")]
     DataStoreLockError,
 
+    /// Storage unit of work misuse
+    #[error("Storage unit of work error: {0}")]
+    UnitOfWorkError(&'static str),
+
+    /// Parsing thread stopped before finishing its chunk of the input
+    #[error("Parallel parsing error")]
+    ParallelReadError,
+
+    /// Opening balance violating account invariants
+    #[error("Invalid opening balance of client {0}: {1}")]
+    InvalidOpeningBalance(Client, &'static str),
+
+    /// Negative or non-finite credit limit
+    #[error("Invalid credit limit of client {0}: {1}")]
+    InvalidCreditLimit(Client, Amount),
+
+    /// Client with more than one account in a set of accounts
+    #[error("Duplicate account of client {0}")]
+    DuplicateAccount(Client),
+
+    /// Error in one of several input files
+    #[error("{0}: {1}")]
+    InputFileError(String, Box<HelaError>),
+
+    /// Input file in a format that cannot be read
+    #[error("Unsupported input format of {0}: {1}")]
+    UnsupportedInputFormat(String, &'static str),
+
+    /// Input pattern or directory without any files
+    #[error("No input files match '{0}'")]
+    NoInputFiles(String),
+
+    /// Invalid input file pattern
+    #[error("Invalid input file pattern: {0}")]
+    PatternError(glob::PatternError),
+
     /// Missing Transaction
     #[error("Transaction Not Found for ID: {0}")]
     TransactionNotFound(TransactionId),
@@ -24,10 +60,104 @@ pub enum HelaError {
     #[error("Insufficient Funds in Acount for Client: {0}")]
     InsufficientAccountFunds(Client),
 
+    /// Administrative transaction outside the authorised admin input
+    #[error("Unauthorised administrative Transaction: {0}")]
+    UnauthorisedTransaction(TransactionId),
+
+    /// Malformed administrative transaction
+    #[error("Invalid administrative Transaction: {0}")]
+    InvalidAdminTransaction(TransactionId),
+
+    /// Account closure with funds held under dispute
+    #[error("Cannot close Acount with held funds for Client: {0}")]
+    HeldFunds(Client),
+
+    /// Account closure with a negative available amount, drawn on its credit line
+    #[error("Cannot close Acount with negative balance for Client: {0}")]
+    NegativeBalance(Client),
+
+    /// Transaction timestamp earlier than the client's previous transaction
+    #[error("Out of order timestamp on Transaction: {0}")]
+    OutOfOrderTimestamp(TransactionId),
+
+    /// Dispute raised after the dispute window of a transaction
+    #[error("Dispute window expired for Transaction: {0}")]
+    DisputeWindowExpired(TransactionId),
+
+    /// Chargeback after the chargeback window of a dispute
+    #[error("Chargeback window expired for Transaction: {0}")]
+    ChargebackWindowExpired(TransactionId),
+
+    /// Deposit or withdrawal without an amount
+    #[error("Missing amount for Transaction: {0}")]
+    MissingAmount(TransactionId),
+
+    /// Negative, non-finite or implausibly large amount
+    #[error("Invalid amount for Transaction: {0}")]
+    InvalidAmount(TransactionId),
+
+    /// Dispute, resolve or chargeback by a client other than the one that made the transaction
+    #[error("Client does not match disputed Transaction: {0}")]
+    ClientMismatch(TransactionId),
+
+    /// Dispute, resolve or chargeback amount exceeding what the transaction allows
+    #[error("Invalid dispute amount for Transaction: {0}")]
+    InvalidDisputeAmount(TransactionId),
+
+    /// Refund of an unknown deposit, another client's deposit, or more than is left to refund
+    #[error("Invalid refund for Transaction: {0}")]
+    InvalidRefund(TransactionId),
+
+    /// Locked Account
+    #[error("Acount is locked for Client: {0}")]
+    AccountLocked(Client),
+
+    /// Transfer without an amount or destination, or to the same client
+    #[error("Invalid transfer for Transaction: {0}")]
+    InvalidTransfer(TransactionId),
+
     /// CSV Data Error
     #[error("Error when processing CSV data: {0}")]
     CsvError(csv::Error),
 
+    /// JSON Lines Data Error, with the line it occurred on
+    #[error("Error when processing JSON data on line {0}: {1}")]
+    JsonError(u64, serde_json::Error),
+
+    /// XML Data Error
+    #[error("Error when processing XML data: {0}")]
+    XmlError(quick_xml::Error),
+
+    /// Bank statement entry that cannot be mapped to a transaction
+    #[error("Error when processing bank statement: {0}")]
+    StatementError(String),
+
+    /// Parquet Data Error
+    #[cfg(feature = "parquet")]
+    #[error("Error when processing Parquet data: {0}")]
+    ParquetError(parquet::errors::ParquetError),
+
+    /// Arrow Data Error, e.g. a missing column or a value of the wrong type
+    #[cfg(feature = "parquet")]
+    #[error("Error when processing Parquet data: {0}")]
+    ArrowError(arrow_schema::ArrowError),
+
+    /// Malformed binary transactions data
+    #[error("Error when processing binary data: {0}")]
+    BinaryError(String),
+
+    /// Data format left out of this build
+    #[error("Support for {0} was not built in")]
+    UnsupportedFormat(&'static str),
+
+    /// Invalid CSV dialect settings
+    #[error("Invalid CSV dialect: {0}")]
+    DialectError(String),
+
+    /// Configuration file error
+    #[error("Error when parsing configuration: {0}")]
+    ConfigError(toml::de::Error),
+
     /// IO Errors
     #[error(transparent)]
     IoError(#[from] std::io::Error),
@@ -36,3 +166,32 @@ pub enum HelaError {
     #[error(transparent)]
     Utf8Error(#[from] std::string::FromUtf8Error),
 }
+
+impl HelaError {
+    /// Whether the error rejects a single transaction rather than failing the whole run
+    pub fn is_rejection(&self) -> bool {
+        self.rejection_reason().is_some()
+    }
+
+    /// Name of the reason a transaction was rejected, if the error rejects a single transaction
+    pub fn rejection_reason(&self) -> Option<&'static str> {
+        Some(match self {
+            HelaError::InsufficientAccountFunds(_) => "insufficient_funds",
+            HelaError::UnauthorisedTransaction(_) => "unauthorised",
+            HelaError::OutOfOrderTimestamp(_) => "out_of_order_timestamp",
+            HelaError::DisputeWindowExpired(_) => "dispute_window_expired",
+            HelaError::ChargebackWindowExpired(_) => "chargeback_window_expired",
+            HelaError::MissingAmount(_) => "missing_amount",
+            HelaError::InvalidAmount(_) => "invalid_amount",
+            HelaError::ClientMismatch(_) => "client_mismatch",
+            HelaError::InvalidDisputeAmount(_) => "invalid_dispute_amount",
+            HelaError::InvalidRefund(_) => "invalid_refund",
+            HelaError::AccountLocked(_) => "account_locked",
+            HelaError::InvalidTransfer(_) => "invalid_transfer",
+            HelaError::InvalidAdminTransaction(_) => "invalid_admin_transaction",
+            HelaError::HeldFunds(_) => "held_funds",
+            HelaError::NegativeBalance(_) => "negative_balance",
+            _ => return None,
+        })
+    }
+}
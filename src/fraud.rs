@@ -0,0 +1,335 @@
+//! Fraud heuristics evaluated after each transaction.
+//!
+//! Rules are loaded from a TOML file:
+//!
+//! ```toml
+//! [[rules]]
+//! rule = "dispute_count"
+//! disputes = 3
+//! window = 10
+//!
+//! [[rules]]
+//! rule = "dispute_ratio"
+//! threshold = 0.5
+//! min_deposits = 4
+//!
+//! [[rules]]
+//! rule = "withdrawal_after_large_deposit"
+//! amount = 10000.0
+//! ```
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Amount, Client, Fallible, HelaError, Transaction, TransactionId, TransactionType};
+
+/// A fraud heuristic that can trigger an account lock
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum FraudRule {
+    /// At least `disputes` disputes within the client's last `window` transactions
+    DisputeCount {
+        /// Number of disputes that triggers the rule
+        disputes: usize,
+        /// Number of recent transactions considered
+        window: usize,
+    },
+    /// Ratio of disputes to deposits above `threshold`, once the client
+    /// has made at least `min_deposits` deposits
+    DisputeRatio {
+        /// Disputes to deposits ratio that triggers the rule
+        threshold: f64,
+        /// Minimum number of deposits before the rule applies
+        min_deposits: usize,
+    },
+    /// A withdrawal immediately following a deposit of at least `amount`
+    WithdrawalAfterLargeDeposit {
+        /// Smallest deposit considered large
+        amount: Amount,
+    },
+}
+
+impl FraudRule {
+    /// Rule name, as used in the configuration file
+    pub fn name(&self) -> &'static str {
+        match self {
+            FraudRule::DisputeCount { .. } => "dispute_count",
+            FraudRule::DisputeRatio { .. } => "dispute_ratio",
+            FraudRule::WithdrawalAfterLargeDeposit { .. } => "withdrawal_after_large_deposit",
+        }
+    }
+
+    /// Check whether a client's activity triggers the rule
+    pub fn is_triggered(&self, activity: &ClientActivity) -> bool {
+        match *self {
+            FraudRule::DisputeCount { disputes, window } => {
+                let recent_disputes = activity
+                    .recent
+                    .iter()
+                    .rev()
+                    .take(window)
+                    .filter(|txn| txn.r#type == TransactionType::Dispute)
+                    .count();
+                recent_disputes >= disputes
+            }
+            FraudRule::DisputeRatio {
+                threshold,
+                min_deposits,
+            } => {
+                activity.deposits > 0
+                    && activity.deposits >= min_deposits
+                    && activity.disputes as f64 / activity.deposits as f64 > threshold
+            }
+            FraudRule::WithdrawalAfterLargeDeposit { amount } => {
+                let mut recent = activity.recent.iter().rev();
+                match (recent.next(), recent.next()) {
+                    (Some(last), Some(prev)) => {
+                        last.r#type == TransactionType::Withdrawal
+                            && prev.r#type == TransactionType::Deposit
+                            && prev.amount.unwrap_or_default() >= amount
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    fn window(&self) -> usize {
+        match *self {
+            FraudRule::DisputeCount { window, .. } => window,
+            FraudRule::DisputeRatio { .. } => 0,
+            FraudRule::WithdrawalAfterLargeDeposit { .. } => 2,
+        }
+    }
+}
+
+/// Fraud rules configuration
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct FraudRules {
+    /// Rules evaluated after each transaction
+    #[serde(default)]
+    pub rules: Vec<FraudRule>,
+}
+
+impl FraudRules {
+    /// Load rules from a TOML configuration file
+    pub fn from_path(fname: &str) -> Fallible<FraudRules> {
+        let config = std::fs::read_to_string(fname)?;
+        toml::from_str(&config).map_err(HelaError::ConfigError)
+    }
+}
+
+/// A client's transaction history as seen by the fraud rules
+#[derive(Clone, Debug, Default)]
+pub struct ClientActivity {
+    /// Most recent transactions, oldest first
+    pub recent: VecDeque<Transaction>,
+    /// Number of deposits made
+    pub deposits: usize,
+    /// Number of disputes raised
+    pub disputes: usize,
+}
+
+impl ClientActivity {
+    /// Record a transaction, keeping at most `window` recent transactions
+    pub fn record(&mut self, txn: Transaction, window: usize) {
+        match txn.r#type {
+            TransactionType::Deposit => self.deposits += 1,
+            TransactionType::Dispute => self.disputes += 1,
+            _ => {}
+        }
+        self.recent.push_back(txn);
+        while self.recent.len() > window {
+            self.recent.pop_front();
+        }
+    }
+}
+
+/// A record of a fraud rule triggering on a transaction
+#[derive(Clone, Debug, Serialize, PartialEq)]
+pub struct Alert {
+    /// Client
+    pub client: Client,
+    /// Transaction that triggered the rule
+    pub tx: TransactionId,
+    /// Rule name
+    pub rule: String,
+}
+
+/// Evaluates fraud rules against each client's transaction history
+#[derive(Clone, Debug, Default)]
+pub struct FraudDetector {
+    rules: Vec<FraudRule>,
+    window: usize,
+    activity: HashMap<Client, ClientActivity>,
+}
+
+impl FraudDetector {
+    /// create a detector evaluating the given rules
+    pub fn new(rules: FraudRules) -> FraudDetector {
+        let window = rules.rules.iter().map(FraudRule::window).max().unwrap_or(0);
+        FraudDetector {
+            rules: rules.rules,
+            window,
+            activity: HashMap::new(),
+        }
+    }
+
+    /// Record a transaction and return an alert for each rule it triggers
+    pub fn observe(&mut self, txn: &Transaction) -> Vec<Alert> {
+        let activity = self.activity.entry(txn.client).or_default();
+        activity.record(*txn, self.window);
+        self.rules
+            .iter()
+            .filter(|rule| rule.is_triggered(activity))
+            .map(|rule| Alert {
+                client: txn.client,
+                tx: txn.id,
+                rule: rule.name().to_string(),
+            })
+            .collect()
+    }
+
+    /// Activity of a client, if any was observed
+    pub(crate) fn activity(&self, client: Client) -> Option<&ClientActivity> {
+        self.activity.get(&client)
+    }
+
+    /// Restore the activity of a client as it was before a transaction was observed
+    pub(crate) fn restore(&mut self, client: Client, activity: Option<ClientActivity>) {
+        match activity {
+            Some(activity) => self.activity.insert(client, activity),
+            None => self.activity.remove(&client),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InMemoryStore, PaymentEngine};
+
+    fn txn(r#type: TransactionType, id: TransactionId, amount: Option<Amount>) -> Transaction {
+        Transaction {
+            r#type,
+            client: 1,
+            id,
+            amount,
+            timestamp: None,
+            destination: None,
+            disputed: 0.0,
+            disputed_at: None,
+            charged_back: 0.0,
+            refunded: 0.0,
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules: FraudRules = toml::from_str(
+            "[[rules]]\nrule = \"dispute_count\"\ndisputes = 2\nwindow = 5\n\
+             [[rules]]\nrule = \"withdrawal_after_large_deposit\"\namount = 100.0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            rules.rules,
+            vec![
+                FraudRule::DisputeCount {
+                    disputes: 2,
+                    window: 5
+                },
+                FraudRule::WithdrawalAfterLargeDeposit { amount: 100.0 },
+            ]
+        );
+    }
+
+    #[test]
+    fn dispute_count() {
+        let rule = FraudRule::DisputeCount {
+            disputes: 2,
+            window: 3,
+        };
+        let mut activity = ClientActivity::default();
+        activity.record(txn(TransactionType::Dispute, 1, None), 3);
+        activity.record(txn(TransactionType::Deposit, 2, Some(1.0)), 3);
+        activity.record(txn(TransactionType::Deposit, 3, Some(1.0)), 3);
+        activity.record(txn(TransactionType::Dispute, 2, None), 3);
+        assert!(!rule.is_triggered(&activity));
+        activity.record(txn(TransactionType::Dispute, 3, None), 3);
+        assert!(rule.is_triggered(&activity));
+    }
+
+    #[test]
+    fn dispute_ratio() {
+        let rule = FraudRule::DisputeRatio {
+            threshold: 0.5,
+            min_deposits: 2,
+        };
+        let mut activity = ClientActivity::default();
+        activity.record(txn(TransactionType::Deposit, 1, Some(1.0)), 0);
+        activity.record(txn(TransactionType::Dispute, 1, None), 0);
+        assert!(!rule.is_triggered(&activity));
+        activity.record(txn(TransactionType::Deposit, 2, Some(1.0)), 0);
+        activity.record(txn(TransactionType::Dispute, 2, None), 0);
+        assert!(rule.is_triggered(&activity));
+    }
+
+    #[test]
+    fn withdrawal_after_large_deposit() {
+        let rule = FraudRule::WithdrawalAfterLargeDeposit { amount: 100.0 };
+        let mut activity = ClientActivity::default();
+        activity.record(txn(TransactionType::Deposit, 1, Some(100.0)), 2);
+        activity.record(txn(TransactionType::Deposit, 2, Some(1.0)), 2);
+        activity.record(txn(TransactionType::Withdrawal, 3, Some(1.0)), 2);
+        assert!(!rule.is_triggered(&activity));
+        activity.record(txn(TransactionType::Deposit, 4, Some(100.0)), 2);
+        activity.record(txn(TransactionType::Withdrawal, 5, Some(50.0)), 2);
+        assert!(rule.is_triggered(&activity));
+    }
+
+    #[test]
+    fn engine_locks_account() -> Fallible<()> {
+        let rules = FraudRules {
+            rules: vec![FraudRule::WithdrawalAfterLargeDeposit { amount: 100.0 }],
+        };
+        let mut engine = PaymentEngine::new(Box::new(InMemoryStore::new()))
+            .with_fraud_detector(FraudDetector::new(rules));
+        engine.execute_transaction(txn(TransactionType::Deposit, 1, Some(100.0)))?;
+        engine.execute_transaction(txn(TransactionType::Withdrawal, 2, Some(90.0)))?;
+        let acc = engine.accounts()?.next().unwrap();
+        assert!(acc.locked);
+        assert_eq!(
+            engine.alerts(),
+            &[Alert {
+                client: 1,
+                tx: 2,
+                rule: "withdrawal_after_large_deposit".to_string(),
+            }]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ignored_transactions_do_not_count() -> Fallible<()> {
+        let rules = FraudRules {
+            rules: vec![
+                FraudRule::DisputeCount {
+                    disputes: 2,
+                    window: 5,
+                },
+                FraudRule::WithdrawalAfterLargeDeposit { amount: 100.0 },
+            ],
+        };
+        let mut engine = PaymentEngine::new(Box::new(InMemoryStore::new()))
+            .with_fraud_detector(FraudDetector::new(rules));
+        engine.execute_transaction(txn(TransactionType::Deposit, 1, Some(100.0)))?;
+        // refused for insufficient funds, then disputes of unknown transactions
+        engine.execute_transaction(txn(TransactionType::Withdrawal, 2, Some(500.0)))?;
+        engine.execute_transaction(txn(TransactionType::Dispute, 8, None))?;
+        engine.execute_transaction(txn(TransactionType::Dispute, 9, None))?;
+        let acc = engine.accounts()?.next().unwrap();
+        assert!(!acc.locked);
+        assert!(engine.alerts().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,167 @@
+//! Synthetic transaction workloads, for benchmarks and load testing.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::{Amount, Client, Transaction, TransactionId, TransactionType};
+
+/// Shape of a synthetic workload
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Workload {
+    /// Number of distinct clients
+    pub clients: Client,
+    /// Number of transactions, including disputes, resolves and chargebacks
+    pub rows: usize,
+    /// Fraction of deposits that are later disputed
+    pub dispute_rate: f64,
+    /// Fraction of disputes that end in a chargeback rather than a resolve
+    pub chargeback_rate: f64,
+    /// Fraction of fresh transactions that are withdrawals rather than deposits
+    pub withdrawal_rate: f64,
+    /// Seed of the random generator, the same seed gives the same workload
+    pub seed: u64,
+}
+
+impl Default for Workload {
+    fn default() -> Self {
+        Workload {
+            clients: 1_000,
+            rows: 100_000,
+            dispute_rate: 0.01,
+            chargeback_rate: 0.2,
+            withdrawal_rate: 0.3,
+            seed: 0,
+        }
+    }
+}
+
+/// Rows after a deposit within which a dispute, and after a dispute within which
+/// its resolve or chargeback, is emitted
+const FOLLOW_UP_ROWS: usize = 1_000;
+
+/// Dispute, resolve or chargeback waiting to be emitted at a later row
+type FollowUp = Reverse<(usize, TransactionId, Client, u8)>;
+
+const DISPUTE: u8 = 0;
+const CLOSE: u8 = 1;
+
+/// Generates the transactions of a workload
+pub struct WorkloadGenerator {
+    workload: Workload,
+    rng: StdRng,
+    row: usize,
+    next_id: TransactionId,
+    follow_ups: BinaryHeap<FollowUp>,
+}
+
+impl WorkloadGenerator {
+    /// create a generator for `workload`
+    pub fn new(workload: Workload) -> WorkloadGenerator {
+        WorkloadGenerator {
+            workload,
+            rng: StdRng::seed_from_u64(workload.seed),
+            row: 0,
+            next_id: 1,
+            follow_ups: BinaryHeap::new(),
+        }
+    }
+
+    fn follow_up(&mut self, id: TransactionId, client: Client, kind: u8) {
+        let due = self.row + self.rng.gen_range(1..=FOLLOW_UP_ROWS);
+        self.follow_ups.push(Reverse((due, id, client, kind)));
+    }
+
+    /// amount with four decimal places
+    fn amount(&mut self, max: Amount) -> Amount {
+        (self.rng.gen_range(0.0001..max) * 10_000.0).round() / 10_000.0
+    }
+
+    fn fresh(&mut self) -> Transaction {
+        let client = self.rng.gen_range(1..=self.workload.clients.max(1));
+        let id = self.next_id;
+        self.next_id += 1;
+        if self.rng.gen_bool(self.workload.withdrawal_rate) {
+            let amount = self.amount(500.0);
+            return transaction(TransactionType::Withdrawal, client, id, Some(amount));
+        }
+        if self.rng.gen_bool(self.workload.dispute_rate) {
+            self.follow_up(id, client, DISPUTE);
+        }
+        let amount = self.amount(1_000.0);
+        transaction(TransactionType::Deposit, client, id, Some(amount))
+    }
+}
+
+fn transaction(
+    r#type: TransactionType,
+    client: Client,
+    id: TransactionId,
+    amount: Option<Amount>,
+) -> Transaction {
+    Transaction {
+        r#type,
+        client,
+        id,
+        amount,
+        timestamp: None,
+        destination: None,
+        disputed: 0.0,
+        disputed_at: None,
+        charged_back: 0.0,
+        refunded: 0.0,
+    }
+}
+
+impl Iterator for WorkloadGenerator {
+    type Item = Transaction;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.row >= self.workload.rows {
+            return None;
+        }
+        let due = match self.follow_ups.peek() {
+            Some(Reverse((due, ..))) => *due <= self.row,
+            None => false,
+        };
+        let txn = if due {
+            let Reverse((_, id, client, kind)) = self.follow_ups.pop().unwrap();
+            if kind == DISPUTE {
+                self.follow_up(id, client, CLOSE);
+                transaction(TransactionType::Dispute, client, id, None)
+            } else if self.rng.gen_bool(self.workload.chargeback_rate) {
+                transaction(TransactionType::Chargeback, client, id, None)
+            } else {
+                transaction(TransactionType::Resolve, client, id, None)
+            }
+        } else {
+            self.fresh()
+        };
+        self.row += 1;
+        Some(txn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generates_workload() {
+        let workload = Workload {
+            clients: 10,
+            rows: 5_000,
+            dispute_rate: 0.5,
+            ..Default::default()
+        };
+        let txns: Vec<_> = WorkloadGenerator::new(workload).collect();
+        assert_eq!(txns.len(), 5_000);
+        assert!(txns.iter().all(|txn| (1..=10).contains(&txn.client)));
+        let count = |r#type| txns.iter().filter(|txn| txn.r#type == r#type).count();
+        assert!(count(TransactionType::Dispute) > 0);
+        assert!(count(TransactionType::Chargeback) > 0);
+        assert!(count(TransactionType::Resolve) > 0);
+        assert_eq!(txns, WorkloadGenerator::new(workload).collect::<Vec<_>>());
+    }
+}
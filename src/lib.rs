@@ -1,13 +1,40 @@
 #![warn(missing_docs)]
 //! Hela lib
+mod binary;
+mod camt;
+#[cfg(feature = "parquet")]
+mod columnar;
+pub mod conformance;
 mod core;
 mod data;
+mod dialect;
+mod diff;
 mod engine;
 mod errors;
+mod fraud;
+mod generator;
+mod merge;
+mod parallel;
+mod retention;
+mod stats;
 mod storage;
 
+pub use crate::binary::{BinaryReader, BinaryWriter, BINARY_MAGIC, BINARY_VERSION};
+pub use crate::camt::{Camt053Reader, StatementIds};
+#[cfg(feature = "parquet")]
+pub use crate::columnar::{ParquetReader, ParquetWriter};
 pub use crate::core::*;
-pub use crate::data::{CsvDataReader, CsvWriterStdout};
+pub use crate::data::{
+    CsvDataReader, CsvRecordReader, CsvWriterStdout, InputFormat, InputOptions, JsonLinesReader,
+};
+pub use crate::dialect::CsvDialect;
+pub use crate::diff::{diff_accounts, AccountDelta, AccountDiff, DiffSummary};
 pub use crate::engine::PaymentEngine;
 pub use crate::errors::{Fallible, HelaError};
+pub use crate::fraud::{Alert, ClientActivity, FraudDetector, FraudRule, FraudRules};
+pub use crate::generator::{Workload, WorkloadGenerator};
+pub use crate::merge::{expand_inputs, MergeOrder, MergedSource};
+pub use crate::parallel::ParallelCsvReader;
+pub use crate::retention::{RetentionPolicy, TransactionRetention};
+pub use crate::stats::{IgnoreReason, RunStats};
 pub use crate::storage::InMemoryStore;
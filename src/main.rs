@@ -1,15 +1,23 @@
 #[macro_use]
 extern crate clap;
-use clap::{App, Arg};
-use hela::{CsvDataReader, CsvWriterStdout, Fallible, InMemoryStore, PaymentEngine};
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use hela::{
+    diff_accounts, expand_inputs, Account, AccountStore, AdminTransaction, BinaryWriter,
+    CreditLimit, CsvDialect, CsvRecordReader, CsvWriterStdout, Fallible, FraudDetector, FraudRules,
+    HelaError, InMemoryStore, InputFormat, InputOptions, MergeOrder, MergedSource, PaymentEngine,
+    Rejection, RetentionPolicy, StatementAccount, StatementIds, Workload, WorkloadGenerator,
+};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::Instant;
+use tracing::level_filters::LevelFilter;
 
 fn main() {
-    match inner_main() {
-        Err(e) => {
-            eprintln!("{}", e);
-            std::process::exit(1);
-        }
-        _ => {}
+    if let Err(e) = inner_main() {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
 fn inner_main() -> Fallible<()> {
@@ -17,28 +25,544 @@ fn inner_main() -> Fallible<()> {
         .version(crate_version!())
         .author(crate_authors!())
         .about(crate_description!())
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("gen")
+                .about("Writes a synthetic transactions file to stdout")
+                .arg(
+                    Arg::with_name("clients")
+                        .long("clients")
+                        .value_name("COUNT")
+                        .help("Sets the number of clients")
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::with_name("rows")
+                        .long("rows")
+                        .value_name("COUNT")
+                        .help("Sets the number of transactions")
+                        .default_value("100000"),
+                )
+                .arg(
+                    Arg::with_name("dispute-rate")
+                        .long("dispute-rate")
+                        .value_name("FRACTION")
+                        .help("Sets the fraction of deposits that are disputed")
+                        .default_value("0.01"),
+                )
+                .arg(
+                    Arg::with_name("chargeback-rate")
+                        .long("chargeback-rate")
+                        .value_name("FRACTION")
+                        .help("Sets the fraction of disputes that are charged back")
+                        .default_value("0.2"),
+                )
+                .arg(
+                    Arg::with_name("seed")
+                        .long("seed")
+                        .value_name("SEED")
+                        .help("Sets the random seed, the same seed generates the same file")
+                        .default_value("0"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Writes the changes between two accounts files to stdout, and their summary to stderr")
+                .arg(
+                    Arg::with_name("BEFORE")
+                        .help("Sets the accounts file to compare from")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("AFTER")
+                        .help("Sets the accounts file to compare to")
+                        .required(true)
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Converts a transactions file between CSV and the binary format")
+                .arg(
+                    Arg::with_name("INPUT")
+                        .help("Sets the input file, in any supported input format")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("OUTPUT")
+                        .help("Sets the output file")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("FORMAT")
+                        .help("Sets the output format, binary for .hela files and CSV otherwise by default")
+                        .possible_values(&["csv", "binary"]),
+                )
+                .arg(
+                    Arg::with_name("dialect")
+                        .long("dialect")
+                        .value_name("FILE")
+                        .help("Sets a TOML file of the CSV dialect and column names of the input file")
+                        .takes_value(true),
+                ),
+        )
         .arg(
             Arg::with_name("INPUT")
-                .help("Sets the input file to use")
+                .help("Sets the input files to use, which may be directories or glob patterns")
                 .required(true)
+                .multiple(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("merge")
+                .long("merge")
+                .value_name("ORDER")
+                .help("Sets the order in which transactions of several input files are processed")
+                .possible_values(&["concat", "timestamp", "sequence"])
+                .default_value("concat"),
+        )
+        .arg(
+            Arg::with_name("input-format")
+                .long("input-format")
+                .value_name("FORMAT")
+                .help("Sets the format of the input file, detected from its name or contents by default")
+                .possible_values(&["auto", "csv", "jsonl", "camt053", "parquet", "binary"])
+                .default_value("auto"),
+        )
+        .arg(
+            Arg::with_name("output-format")
+                .long("output-format")
+                .value_name("FORMAT")
+                .help("Sets the format of the accounts written to stdout, and of the rejections file")
+                .possible_values(&["csv", "parquet"])
+                .default_value("csv"),
+        )
+        .arg(
+            Arg::with_name("statement-accounts")
+                .long("statement-accounts")
+                .value_name("FILE")
+                .help("Sets a CSV file of the client owning each bank statement account (account,client)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("statement-first-id")
+                .long("statement-first-id")
+                .value_name("ID")
+                .help("Sets the transaction id of the first bank statement entry, later entries of all statements following it")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("dialect")
+                .long("dialect")
+                .value_name("FILE")
+                .help("Sets a TOML file of the CSV dialect and column names of the input file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("delimiter")
+                .long("delimiter")
+                .value_name("CHAR")
+                .help("Sets the field delimiter of the input file, overriding the dialect")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("decimal-separator")
+                .long("decimal-separator")
+                .value_name("CHAR")
+                .help("Sets the decimal separator of amounts in the input file, overriding the dialect")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("COUNT")
+                .help("Parses CSV input files on this many threads, memory-mapping them when above one")
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("opening-balances")
+                .long("opening-balances")
+                .value_name("FILE")
+                .help(
+                    "Sets a CSV file of the accounts to start from, as written by a previous run. \
+                     Accounts with held funds are rejected, as their disputes are not carried over",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("credit-limits")
+                .long("credit-limits")
+                .value_name("FILE")
+                .help("Sets a CSV file of client credit limits (client,credit_limit)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fraud-rules")
+                .long("fraud-rules")
+                .value_name("FILE")
+                .help("Sets a TOML file of fraud rules that lock accounts")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("alerts")
+                .long("alerts")
+                .value_name("FILE")
+                .help("Writes fraud alerts to a CSV file instead of stderr")
+                .takes_value(true)
+                .requires("fraud-rules"),
+        )
+        .arg(
+            Arg::with_name("dispute-window")
+                .long("dispute-window")
+                .value_name("SECONDS")
+                .help("Rejects disputes raised longer than this after the disputed transaction")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("chargeback-window")
+                .long("chargeback-window")
+                .value_name("SECONDS")
+                .help("Rejects chargebacks longer than this after the dispute")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retain")
+                .long("retain")
+                .value_name("TYPES")
+                .help("Sets which transactions are retained for disputes")
+                .possible_values(&["all", "deposits"])
+                .default_value("all"),
+        )
+        .arg(
+            Arg::with_name("evict-expired")
+                .long("evict-expired")
+                .help("Evicts retained transactions once their dispute window has passed")
+                .requires("dispute-window"),
+        )
+        .arg(
+            Arg::with_name("memory-usage")
+                .long("memory-usage")
+                .help("Writes the memory used by the store to stderr at the end of the run"),
+        )
+        .arg(
+            Arg::with_name("admin")
+                .long("admin")
+                .value_name("FILE")
+                .help("Sets a CSV file of authorised administrative transactions (unlock, adjust, close)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audit")
+                .long("audit")
+                .value_name("FILE")
+                .help("Writes the audit trail of administrative transactions to a CSV file instead of stderr")
+                .takes_value(true)
+                .requires("admin"),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .long("log-level")
+                .value_name("LEVEL")
+                .help("Logs to stderr what happens to each transaction, at this level and above")
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .default_value("off"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("FORMAT")
+                .help("Sets the format of the logs")
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("stats")
+                .long("stats")
+                .value_name("FILE")
+                .help("Writes statistics of the run to stderr, or as JSON to a file given with --stats=FILE")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true),
+        )
+        .arg(
+            Arg::with_name("rejections")
+                .long("rejections")
+                .value_name("FILE")
+                .help("Writes rejected transactions to a CSV file instead of stderr")
+                .takes_value(true),
+        )
         .get_matches();
-    let input_fname = matches.value_of("INPUT").unwrap();
+    init_logging(&matches);
+    if let Some(matches) = matches.subcommand_matches("gen") {
+        return generate(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("diff") {
+        return diff(matches);
+    }
+    if let Some(matches) = matches.subcommand_matches("convert") {
+        return convert(matches);
+    }
+    let input_fnames = expand_inputs(&matches.values_of("INPUT").unwrap().collect::<Vec<_>>())?;
 
-    let transactions = CsvDataReader::new(&input_fname)?;
-    let store = InMemoryStore::new();
+    let threads = value_t!(matches, "threads", usize).unwrap_or_else(|e| e.exit());
+    let format = match matches.value_of("input-format") {
+        Some("csv") => Some(InputFormat::Csv),
+        Some("jsonl") => Some(InputFormat::JsonLines),
+        Some("camt053") => Some(InputFormat::Camt053),
+        Some("parquet") => Some(InputFormat::Parquet),
+        Some("binary") => Some(InputFormat::Binary),
+        _ => None,
+    };
+    let order = match matches.value_of("merge") {
+        Some("timestamp") => MergeOrder::Timestamp,
+        Some("sequence") => MergeOrder::Sequence,
+        _ => MergeOrder::Concatenate,
+    };
+    let mut options = InputOptions {
+        threads,
+        statement_ids: StatementIds::starting_at(
+            value_t!(matches, "statement-first-id", u32).unwrap_or_else(|e| e.exit()),
+        ),
+        dialect: dialect(&matches)?,
+        ..Default::default()
+    };
+    if let Some(fname) = matches.value_of("statement-accounts") {
+        for account in CsvRecordReader::<StatementAccount>::new(fname)? {
+            let account = account?;
+            options
+                .statement_accounts
+                .insert(account.account, account.client);
+        }
+    }
+    let transactions = MergedSource::open(&input_fnames, format, &options, order)?;
+    let dispute_window = if matches.is_present("dispute-window") {
+        Some(value_t!(matches, "dispute-window", u64).unwrap_or_else(|e| e.exit()))
+    } else {
+        None
+    };
+    let mut store = InMemoryStore::with_retention(RetentionPolicy {
+        withdrawals: matches.value_of("retain") == Some("all"),
+        max_age: dispute_window.filter(|_| matches.is_present("evict-expired")),
+    });
+    if let Some(fname) = matches.value_of("opening-balances") {
+        load_opening_balances(&mut store, fname)?;
+    }
+    if let Some(fname) = matches.value_of("credit-limits") {
+        load_credit_limits(&mut store, fname)?;
+    }
     let mut engine = PaymentEngine::new(Box::new(store));
+    if let Some(window) = dispute_window {
+        engine = engine.with_dispute_window(window);
+    }
+    if matches.is_present("chargeback-window") {
+        let window = value_t!(matches, "chargeback-window", u64).unwrap_or_else(|e| e.exit());
+        engine = engine.with_chargeback_window(window);
+    }
+    if let Some(fname) = matches.value_of("fraud-rules") {
+        engine = engine.with_fraud_detector(FraudDetector::new(FraudRules::from_path(fname)?));
+    }
 
+    let started = Instant::now();
     engine.execute_transactions(transactions)?;
+    let elapsed = started.elapsed();
+    if let Some(fname) = matches.value_of("admin") {
+        engine.execute_admin_transactions(CsvRecordReader::<AdminTransaction>::new(fname)?)?;
+    }
+
+    write_report(engine.alerts(), matches.value_of("alerts"))?;
+    write_report(engine.audit_trail(), matches.value_of("audit"))?;
+    let parquet = matches.value_of("output-format") == Some("parquet");
+    match matches.value_of("rejections") {
+        Some(fname) if parquet => write_parquet_rejections(engine.rejections(), fname)?,
+        fname => write_report(engine.rejections(), fname)?,
+    }
+    if matches.is_present("memory-usage") {
+        if let Some(usage) = engine.memory_usage() {
+            eprintln!(
+                "memory: {} accounts, {} retained transactions, {} bytes",
+                usage.accounts, usage.transactions, usage.bytes
+            );
+        }
+    }
 
-    CsvWriterStdout::write(engine.accounts()?, Some(std::io::stdout()))?;
+    if matches.is_present("stats") {
+        let stats = engine.stats()?.with_elapsed(elapsed);
+        match matches.value_of("stats") {
+            Some(fname) => {
+                serde_json::to_writer_pretty(BufWriter::new(File::create(fname)?), &stats)
+                    .map_err(|e| HelaError::IoError(e.into()))?
+            }
+            None => eprintln!("{}", stats),
+        }
+    }
+
+    if parquet {
+        write_parquet_accounts(&engine, matches.is_present("credit-limits"))?;
+    } else if matches.is_present("credit-limits") {
+        CsvWriterStdout::write_with_credit_limit(engine.accounts()?, Some(std::io::stdout()))?;
+    } else {
+        CsvWriterStdout::write(engine.accounts()?, Some(std::io::stdout()))?;
+    }
+    Ok(())
+}
+
+/// Log to stderr, keeping stdout for the output
+fn init_logging(matches: &ArgMatches) {
+    let level = value_t!(matches, "log-level", LevelFilter).unwrap_or_else(|e| e.exit());
+    if level == LevelFilter::OFF {
+        return;
+    }
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(std::io::stderr);
+    if matches.value_of("log-format") == Some("json") {
+        subscriber.json().init();
+    } else {
+        subscriber.init();
+    }
+}
+
+/// Set the credit limits of a limits file, rejecting negative and non-finite limits
+fn load_credit_limits(store: &mut dyn AccountStore, fname: &str) -> Fallible<()> {
+    for limit in CsvRecordReader::<CreditLimit>::new(fname)? {
+        let limit = limit?;
+        store.set_credit_limit(limit.client, limit.credit_limit)?;
+    }
+    Ok(())
+}
+
+/// Store the accounts of a previous run's output, checking their invariants
+fn load_opening_balances(store: &mut dyn AccountStore, fname: &str) -> Fallible<()> {
+    let mut clients = HashSet::new();
+    for acc in CsvRecordReader::<Account>::new(fname)? {
+        let acc = acc?.into_opening_balance()?;
+        if !clients.insert(acc.client) {
+            return Err(HelaError::DuplicateAccount(acc.client));
+        }
+        store.store_account(acc)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "parquet")]
+fn write_parquet_accounts(engine: &PaymentEngine, with_credit_limit: bool) -> Fallible<()> {
+    hela::ParquetWriter::write_accounts(engine.accounts()?, with_credit_limit, std::io::stdout())
+}
+
+#[cfg(feature = "parquet")]
+fn write_parquet_rejections(rejections: &[Rejection], fname: &str) -> Fallible<()> {
+    hela::ParquetWriter::write_rejections(rejections.iter(), File::create(fname)?)
+}
+
+#[cfg(not(feature = "parquet"))]
+fn write_parquet_accounts(_: &PaymentEngine, _: bool) -> Fallible<()> {
+    Err(HelaError::UnsupportedFormat("Parquet"))
+}
+
+#[cfg(not(feature = "parquet"))]
+fn write_parquet_rejections(_: &[Rejection], _: &str) -> Fallible<()> {
+    Err(HelaError::UnsupportedFormat("Parquet"))
+}
+
+/// Write a synthetic workload to stdout
+fn generate(matches: &ArgMatches) -> Fallible<()> {
+    let fraction = |name| {
+        let rate = value_t!(matches, name, f64).unwrap_or_else(|e| e.exit());
+        if !(0.0..=1.0).contains(&rate) {
+            clap::Error::value_validation_auto(format!("--{} must be between 0 and 1", name))
+                .exit();
+        }
+        rate
+    };
+    let workload = Workload {
+        clients: value_t!(matches, "clients", u16).unwrap_or_else(|e| e.exit()),
+        rows: value_t!(matches, "rows", usize).unwrap_or_else(|e| e.exit()),
+        dispute_rate: fraction("dispute-rate"),
+        chargeback_rate: fraction("chargeback-rate"),
+        seed: value_t!(matches, "seed", u64).unwrap_or_else(|e| e.exit()),
+        ..Default::default()
+    };
+    CsvWriterStdout::write_transactions(
+        WorkloadGenerator::new(workload),
+        Some(BufWriter::new(std::io::stdout())),
+    )
+}
+
+/// CSV dialect of the input file, from the dialect file and the options overriding it
+fn dialect(matches: &ArgMatches) -> Fallible<CsvDialect> {
+    let mut dialect = match matches.value_of("dialect") {
+        Some(fname) => CsvDialect::from_path(fname)?,
+        None => CsvDialect::default(),
+    };
+    if matches.is_present("delimiter") {
+        dialect.delimiter = value_t!(matches, "delimiter", char).unwrap_or_else(|e| e.exit());
+    }
+    if matches.is_present("decimal-separator") {
+        dialect.decimal_separator =
+            value_t!(matches, "decimal-separator", char).unwrap_or_else(|e| e.exit());
+    }
+    dialect.validate()?;
+    Ok(dialect)
+}
+
+/// Convert a transactions file to CSV or the binary format
+fn convert(matches: &ArgMatches) -> Fallible<()> {
+    let input_fname = matches.value_of("INPUT").unwrap();
+    let output_fname = matches.value_of("OUTPUT").unwrap();
+    let binary = match matches.value_of("to") {
+        Some(format) => format == "binary",
+        None => output_fname.ends_with(".hela"),
+    };
+    let options = InputOptions {
+        dialect: dialect(matches)?,
+        ..Default::default()
+    };
+    let transactions = InputFormat::detect(input_fname)?.open(input_fname, &options)?;
+    if binary {
+        let mut wtr = BinaryWriter::create(output_fname)?;
+        for txn in transactions {
+            wtr.write(&txn?)?;
+        }
+        wtr.finish()?;
+        return Ok(());
+    }
+    let mut error = None;
+    let txns = transactions.map_while(|txn| txn.map_err(|e| error = Some(e)).ok());
+    CsvWriterStdout::write_transactions(txns, Some(BufWriter::new(File::create(output_fname)?)))?;
+    error.map_or(Ok(()), Err)
+}
+
+/// Write the changes between two accounts files
+fn diff(matches: &ArgMatches) -> Fallible<()> {
+    let read = |name| -> Fallible<Vec<Account>> {
+        CsvRecordReader::<Account>::new(matches.value_of(name).unwrap())?.collect()
+    };
+    let diff = diff_accounts(read("BEFORE")?, read("AFTER")?)?;
+    CsvWriterStdout::write_records(diff.deltas.iter(), Some(std::io::stdout()))?;
+    eprintln!("{}", diff.summary);
     Ok(())
 }
 
+/// Write report records to a CSV file, or to stderr when there are any to report
+fn write_report<T: Serialize>(records: &[T], fname: Option<&str>) -> Fallible<()> {
+    match fname {
+        Some(fname) => CsvWriterStdout::write_records(records.iter(), Some(File::create(fname)?)),
+        None if !records.is_empty() => {
+            CsvWriterStdout::write_records(records.iter(), Some(std::io::stderr()))
+        }
+        None => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use hela::{CsvDataReader, Transaction, TransactionType};
     use std::io::Write;
     use tempfile::NamedTempFile;
 
@@ -90,4 +614,205 @@ mod tests {
       withdrawal,2,4,3.0",
         "client,available,held,total,locked\n1,-0.50,2.00,1.50,false\n2,2.00,0.00,2.00,false\n"
     );
+
+    tst!(
+        test_partial_dispute,
+        "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,4.0\ndispute,1,1,7.0\ndispute,1,1,2.0\nresolve,1,1,1.0\nchargeback,1,1,3.0",
+        "client,available,held,total,locked\n1,5.00,2.00,7.00,true\n"
+    );
+
+    tst!(
+        test_refund,
+        "type,client,tx,amount\ndeposit,1,1,10.0\nrefund,1,1,4.0\nrefund,2,1,1.0\nrefund,1,1,7.0\ndispute,1,1\nresolve,1,1\nrefund,1,1,6.0\ndispute,1,1",
+        "client,available,held,total,locked\n1,0.00,0.00,0.00,false\n"
+    );
+
+    tst!(
+        test_transfer,
+        "type,client,tx,amount,timestamp,destination\ndeposit,1,1,10.0\ntransfer,1,2,4.0,,2\ntransfer,2,3,5.0,,1\ntransfer,1,4,1.0,,1\ntransfer,1,5,1.0",
+        "client,available,held,total,locked\n1,6.00,0.00,6.00,false\n2,4.00,0.00,4.00,false\n"
+    );
+
+    tst!(
+        test_malformed_rows,
+        "type,client,tx,amount\ndeposit,1,1,NaN\ndeposit,1,2\ndeposit,1,3,-5.0\ndeposit,1,4,2.0\ndeposit,2,5,1.0\ndispute,1,5\nchargeback,1,5",
+        "client,available,held,total,locked\n1,2.00,0.00,2.00,false\n2,1.00,0.00,1.00,false\n"
+    );
+
+    #[test]
+    fn test_opening_balances() -> Fallible<()> {
+        let mut accounts_file = NamedTempFile::new()?;
+        accounts_file.write_all(
+            b"client,available,held,total,locked\n1,1.13,0.00,1.13,false\n2,3.00,0.00,3.00,true\n",
+        )?;
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,2,2,1.0")?;
+        let mut store = InMemoryStore::new();
+        load_opening_balances(&mut store, accounts_file.path().to_str().unwrap())?;
+        let mut engine = PaymentEngine::new(Box::new(store));
+        engine.execute_transactions(CsvDataReader::new(input_file.path().to_str().unwrap())?)?;
+        let mut output = vec![];
+        CsvWriterStdout::write(engine.accounts()?, Some(&mut output))?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            "client,available,held,total,locked\n1,2.13,0.00,2.13,false\n2,3.00,0.00,3.00,true\n"
+        );
+        assert_eq!(engine.rejections()[0].tx, 2);
+
+        for accounts in [
+            "client,available,held,total,locked\n1,1.00,0.50,1.00,false\n",
+            "client,available,held,total,locked\n1,1.00,0.50,1.50,false\n",
+            "client,available,held,total,locked\n1,1.00,0.00,1.00,false\n1,2.00,0.00,2.00,false\n",
+        ] {
+            let mut accounts_file = NamedTempFile::new()?;
+            accounts_file.write_all(accounts.as_bytes())?;
+            let res = load_opening_balances(
+                &mut InMemoryStore::new(),
+                accounts_file.path().to_str().unwrap(),
+            );
+            assert!(res.is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_credit_limit() -> Fallible<()> {
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(b"type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,3.0")?;
+        let txns = CsvDataReader::new(input_file.path().to_str().unwrap())?;
+        let mut store = InMemoryStore::new();
+        store.set_credit_limit(1, 2.5)?;
+        let mut engine = PaymentEngine::new(Box::new(store));
+        engine.execute_transactions(txns)?;
+        let mut output = vec![];
+        CsvWriterStdout::write_with_credit_limit(engine.accounts()?, Some(&mut output))?;
+        let data = String::from_utf8(output)?;
+        assert_eq!(
+            data,
+            "client,available,held,total,locked,credit_limit\n1,-2.00,0.00,-2.00,false,2.50\n"
+        );
+
+        for limits in [
+            "client,credit_limit\n1,-10\n",
+            "client,credit_limit\n1,NaN\n",
+        ] {
+            let mut limits_file = NamedTempFile::new()?;
+            limits_file.write_all(limits.as_bytes())?;
+            let mut store = InMemoryStore::new();
+            let res = load_credit_limits(&mut store, limits_file.path().to_str().unwrap());
+            assert!(matches!(res, Err(HelaError::InvalidCreditLimit(1, _))));
+            assert!(store.get_account(1).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_admin_transactions() -> Fallible<()> {
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(
+            b"type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1\nchargeback,1,1\n\
+              deposit,2,2,4.0\nunlock,1,3\ndeposit,3,4,1.0\ndispute,3,4\nwithdrawal,4,5,1.0",
+        )?;
+        let mut admin_file = NamedTempFile::new()?;
+        admin_file.write_all(
+            b"type,client,tx,amount,reason\nunlock,1,100,,chargeback reviewed\n\
+              adjust,1,101,2.5,goodwill credit\nclose,2,102,,client request\n\
+              close,3,103,,held funds\nclose,4,104,,credit line\nclose,5,105,,unknown\n\
+              adjust,1,106,1e300,out of range",
+        )?;
+        let txns = CsvDataReader::new(input_file.path().to_str().unwrap())?;
+        let admin_txns = CsvRecordReader::new(admin_file.path().to_str().unwrap())?;
+        let mut store = InMemoryStore::new();
+        store.set_credit_limit(4, 1.0)?;
+        let mut engine = PaymentEngine::new(Box::new(store));
+        engine.execute_transactions(txns)?;
+        engine.execute_admin_transactions(admin_txns)?;
+        let mut output = vec![];
+        CsvWriterStdout::write(engine.accounts()?, Some(&mut output))?;
+        let data = String::from_utf8(output)?;
+        assert_eq!(
+            data,
+            "client,available,held,total,locked\n1,2.50,0.00,2.50,false\n2,0.00,0.00,0.00,true\n\
+             3,0.00,1.00,1.00,false\n4,-1.00,0.00,-1.00,false\n"
+        );
+        let rejected: Vec<_> = engine.rejections().iter().map(|rej| rej.tx).collect();
+        assert_eq!(rejected, vec![3, 103, 104, 105, 106]);
+        let audit: Vec<_> = engine.audit_trail().iter().map(|txn| txn.amount).collect();
+        assert_eq!(audit, vec![None, Some(2.5), Some(4.0)]);
+
+        // a closed account takes no deposits and pays nothing out
+        for r#type in [TransactionType::Deposit, TransactionType::Withdrawal] {
+            let res = engine.execute_transaction(Transaction {
+                r#type,
+                client: 2,
+                id: 200,
+                amount: Some(1.0),
+                timestamp: None,
+                destination: None,
+                disputed: 0.0,
+                disputed_at: None,
+                charged_back: 0.0,
+                refunded: 0.0,
+            });
+            assert!(matches!(res, Err(HelaError::AccountLocked(2))));
+        }
+        assert_eq!(
+            engine
+                .accounts()?
+                .find(|acc| acc.client == 2)
+                .unwrap()
+                .total,
+            0.0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_evict_expired() -> Fallible<()> {
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(
+            b"type,client,tx,amount,timestamp\ndeposit,1,1,10,50\ndeposit,2,2,5,1000\n\
+              dispute,1,1,,60",
+        )?;
+        let txns = CsvDataReader::new(input_file.path().to_str().unwrap())?;
+        let store = InMemoryStore::with_retention(RetentionPolicy {
+            withdrawals: true,
+            max_age: Some(100),
+        });
+        let mut engine = PaymentEngine::new(Box::new(store)).with_dispute_window(100);
+        engine.execute_transactions(txns)?;
+        let mut output = vec![];
+        CsvWriterStdout::write(engine.accounts()?, Some(&mut output))?;
+        let data = String::from_utf8(output)?;
+        assert_eq!(
+            data,
+            "client,available,held,total,locked\n1,0.00,10.00,10.00,false\n2,5.00,0.00,5.00,false\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_timestamps() -> Fallible<()> {
+        let mut input_file = NamedTempFile::new()?;
+        input_file.write_all(
+            b"type,client,tx,amount,timestamp\ndeposit,1,1,1.0,100\ndeposit,1,2,2.0,200\n\
+              deposit,1,3,4.0,150\ndispute,1,1,,250\ndispute,1,2,,250\n\
+              chargeback,1,2,,400\ndeposit,2,4,8.0,\ndispute,2,4,,",
+        )?;
+        let txns = CsvDataReader::new(input_file.path().to_str().unwrap())?;
+        let mut engine = PaymentEngine::new(Box::new(InMemoryStore::new()))
+            .with_dispute_window(100)
+            .with_chargeback_window(100);
+        engine.execute_transactions(txns)?;
+        let mut output = vec![];
+        CsvWriterStdout::write(engine.accounts()?, Some(&mut output))?;
+        let data = String::from_utf8(output)?;
+        assert_eq!(
+            data,
+            "client,available,held,total,locked\n1,1.00,2.00,3.00,false\n2,0.00,8.00,8.00,false\n"
+        );
+        let rejected: Vec<_> = engine.rejections().iter().map(|r| r.tx).collect();
+        assert_eq!(rejected, vec![3, 1, 2]);
+        Ok(())
+    }
 }
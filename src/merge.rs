@@ -0,0 +1,280 @@
+//! Merging of several transactions input files.
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::path::Path;
+
+use crate::{
+    Fallible, HelaError, InputFormat, InputOptions, Transaction, TransactionSource, TransactionType,
+};
+
+/// Order in which the transactions of several input files are processed
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MergeOrder {
+    /// Every file in turn, in the order given
+    Concatenate,
+    /// By timestamp, rows without one following the previous row of their file
+    Timestamp,
+    /// By the ids of deposits, withdrawals and transfers, rows referencing another
+    /// transaction following the previous row of their file
+    Sequence,
+}
+
+impl MergeOrder {
+    /// Merge key of a transaction, if it has one
+    fn key(self, txn: &Transaction) -> Option<u64> {
+        match self {
+            MergeOrder::Concatenate => None,
+            MergeOrder::Timestamp => txn.timestamp,
+            MergeOrder::Sequence => match txn.r#type {
+                TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Transfer => Some(txn.id as u64),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Expand input paths: directories to the files in them and glob patterns to the files they
+/// match, both in name order. Other paths are kept as given.
+pub fn expand_inputs<S: AsRef<str>>(patterns: &[S]) -> Fallible<Vec<String>> {
+    let mut fnames = vec![];
+    for pattern in patterns {
+        let pattern = pattern.as_ref();
+        let mut matched = vec![];
+        if Path::new(pattern).is_dir() {
+            for entry in std::fs::read_dir(pattern)? {
+                let path = entry?.path();
+                let visible = path
+                    .file_name()
+                    .is_some_and(|name| !name.to_string_lossy().starts_with('.'));
+                if path.is_file() && visible {
+                    matched.push(path.to_string_lossy().into_owned());
+                }
+            }
+            matched.sort();
+        } else if pattern.contains(['*', '?', '[']) {
+            for path in glob::glob(pattern).map_err(HelaError::PatternError)? {
+                let path = path.map_err(|e| HelaError::IoError(e.into()))?;
+                if path.is_file() {
+                    matched.push(path.to_string_lossy().into_owned());
+                }
+            }
+        } else {
+            matched.push(pattern.to_string());
+        }
+        if matched.is_empty() {
+            return Err(HelaError::NoInputFiles(pattern.to_string()));
+        }
+        fnames.append(&mut matched);
+    }
+    Ok(fnames)
+}
+
+/// An input file and the key of its last transaction
+struct Input {
+    fname: String,
+    source: Box<dyn TransactionSource>,
+    key: u64,
+    /// Next transaction, while it waits to be merged
+    next: Option<Fallible<Transaction>>,
+}
+
+/// Transactions of several input files, merged in a deterministic order.
+///
+/// With more than one file, errors are prefixed with the name of the file they were
+/// found in, keeping the position reported by the file's reader.
+pub struct MergedSource {
+    inputs: Vec<Input>,
+    order: MergeOrder,
+    /// Key and index of the files with a transaction waiting, ties going to the earlier file
+    heads: BinaryHeap<Reverse<(u64, usize)>>,
+    /// File being read when concatenating
+    current: usize,
+    /// Whether the heads have been filled when merging
+    started: bool,
+}
+
+impl MergedSource {
+    /// Merge already opened sources, named by their file names
+    pub fn new(sources: Vec<(String, Box<dyn TransactionSource>)>, order: MergeOrder) -> Self {
+        MergedSource {
+            inputs: sources
+                .into_iter()
+                .map(|(fname, source)| Input {
+                    fname,
+                    source,
+                    key: 0,
+                    next: None,
+                })
+                .collect(),
+            order,
+            heads: BinaryHeap::new(),
+            current: 0,
+            started: false,
+        }
+    }
+
+    /// Open input files, detecting the format of each unless one is given
+    pub fn open(
+        fnames: &[String],
+        format: Option<InputFormat>,
+        options: &InputOptions,
+        order: MergeOrder,
+    ) -> Fallible<MergedSource> {
+        let mut sources = vec![];
+        for fname in fnames {
+            let source = format
+                .map_or_else(|| InputFormat::detect(fname), Ok)
+                .and_then(|format| format.open(fname, options));
+            match source {
+                Ok(source) => sources.push((fname.clone(), source)),
+                Err(e) if fnames.len() > 1 => {
+                    return Err(HelaError::InputFileError(fname.clone(), Box::new(e)))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(MergedSource::new(sources, order))
+    }
+
+    fn label(&self, idx: usize, txn: Fallible<Transaction>) -> Fallible<Transaction> {
+        match txn {
+            Err(e) if self.inputs.len() > 1 => Err(HelaError::InputFileError(
+                self.inputs[idx].fname.clone(),
+                Box::new(e),
+            )),
+            txn => txn,
+        }
+    }
+
+    /// Read the next transaction of a file into the heads
+    fn advance(&mut self, idx: usize) {
+        let (order, input) = (self.order, &mut self.inputs[idx]);
+        if let Some(txn) = input.source.next() {
+            if let Ok(Some(key)) = txn.as_ref().map(|txn| order.key(txn)) {
+                input.key = key;
+            }
+            input.next = Some(txn);
+            self.heads.push(Reverse((input.key, idx)));
+        }
+    }
+}
+
+impl Iterator for MergedSource {
+    type Item = Fallible<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.order == MergeOrder::Concatenate {
+            while self.current < self.inputs.len() {
+                if let Some(txn) = self.inputs[self.current].source.next() {
+                    return Some(self.label(self.current, txn));
+                }
+                self.current += 1;
+            }
+            return None;
+        }
+        if !self.started {
+            self.started = true;
+            for idx in 0..self.inputs.len() {
+                self.advance(idx);
+            }
+        }
+        let Reverse((_, idx)) = self.heads.pop()?;
+        let txn = self.inputs[idx].next.take()?;
+        self.advance(idx);
+        Some(self.label(idx, txn))
+    }
+}
+
+impl TransactionSource for MergedSource {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CsvDataReader;
+
+    fn merge(files: &[&'static str], order: MergeOrder) -> Vec<Result<(u32, u16), String>> {
+        let sources = files
+            .iter()
+            .enumerate()
+            .map(|(idx, data)| {
+                let source: Box<dyn TransactionSource> =
+                    Box::new(CsvDataReader::from_reader(data.as_bytes()));
+                (format!("day{}.csv", idx + 1), source)
+            })
+            .collect();
+        MergedSource::new(sources, order)
+            .map(|txn| {
+                txn.map(|txn| (txn.id, txn.client))
+                    .map_err(|e| e.to_string())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merges_in_order() {
+        let files = [
+            "type,client,tx,amount,timestamp\ndeposit,1,1,1.0,10\ndispute,1,1,,\ndeposit,1,4,1.0,40\n",
+            "type,client,tx,amount,timestamp\ndeposit,2,2,1.0,20\ndeposit,2,3,1.0,30\n",
+        ];
+        let ids = |txns: Vec<Result<(u32, u16), String>>| -> Vec<u32> {
+            txns.into_iter().map(|txn| txn.unwrap().0).collect()
+        };
+        assert_eq!(
+            ids(merge(&files, MergeOrder::Concatenate)),
+            vec![1, 1, 4, 2, 3]
+        );
+        assert_eq!(
+            ids(merge(&files, MergeOrder::Timestamp)),
+            vec![1, 1, 2, 3, 4]
+        );
+        assert_eq!(
+            ids(merge(&files, MergeOrder::Sequence)),
+            vec![1, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn labels_errors_with_file() {
+        let txns = merge(
+            &[
+                "type,client,tx,amount\ndeposit,1,1,1.0\n",
+                "type,client,tx,amount\ndeposit,2,2,1.0\ndeposit,2,x,1.0\n",
+            ],
+            MergeOrder::Timestamp,
+        );
+        assert_eq!(txns.len(), 3);
+        let error = txns[2].as_ref().unwrap_err();
+        assert!(error.starts_with("day2.csv: "), "{}", error);
+        assert!(error.contains("line: 3"), "{}", error);
+    }
+
+    #[test]
+    fn expands_directories_and_globs() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["b.csv", "a.csv", "c.jsonl", ".hidden"] {
+            std::fs::write(dir.path().join(name), "")?;
+        }
+        let dir_name = dir.path().to_str().unwrap();
+        let names = |fnames: Vec<String>| -> Vec<String> {
+            fnames
+                .iter()
+                .map(|fname| fname[dir_name.len() + 1..].to_string())
+                .collect()
+        };
+        assert_eq!(
+            names(expand_inputs(&[dir_name])?),
+            vec!["a.csv", "b.csv", "c.jsonl"]
+        );
+        assert_eq!(
+            names(expand_inputs(&[format!("{}/*.csv", dir_name)])?),
+            vec!["a.csv", "b.csv"]
+        );
+        assert!(matches!(
+            expand_inputs(&[format!("{}/*.xml", dir_name)]),
+            Err(HelaError::NoInputFiles(_))
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+//! Parallel parsing of memory-mapped transaction files.
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, SeekFrom};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use memmap2::Mmap;
+
+use crate::{CsvDataReader, CsvDialect, Fallible, HelaError, Transaction, TransactionSource};
+
+/// Bytes per chunk handed to a parsing thread
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Transactions of one chunk, in file order
+struct ParsedChunk {
+    txns: Vec<Fallible<Transaction>>,
+    /// Number of lines in the chunk
+    lines: u64,
+    /// Whether a record failed to parse, so error positions must be worked out again
+    failed: bool,
+}
+
+/// Parse the chunk of `data` between `start` and `end`, where `pos` is where the chunk starts
+fn parse_chunk(
+    data: &[u8],
+    start: usize,
+    end: usize,
+    pos: csv::Position,
+    dialect: &CsvDialect,
+) -> Fallible<ParsedChunk> {
+    let mut rdr = CsvDataReader::from_reader_with_dialect(Cursor::new(&data[..end]), dialect);
+    rdr.seek(SeekFrom::Start(start as u64), pos)?;
+    let txns: Vec<_> = rdr.collect();
+    Ok(ParsedChunk {
+        failed: txns.iter().any(|txn| txn.is_err()),
+        lines: data[start..end].iter().filter(|&&b| b == b'\n').count() as u64,
+        txns,
+    })
+}
+
+/// Parsing threads and the reorder buffer of their chunks
+struct Workers {
+    mmap: Arc<Mmap>,
+    dialect: Arc<CsvDialect>,
+    chunks: Vec<(usize, usize)>,
+    jobs: Option<Sender<usize>>,
+    results: Receiver<(usize, Fallible<ParsedChunk>)>,
+    handles: Vec<JoinHandle<()>>,
+    reorder: HashMap<usize, Fallible<ParsedChunk>>,
+    /// Next chunk to be yielded, and to be handed to a thread
+    next: usize,
+    queued: usize,
+    /// Position at which the next chunk starts
+    pos: csv::Position,
+    current: std::vec::IntoIter<Fallible<Transaction>>,
+}
+
+impl Workers {
+    fn start(
+        mmap: Mmap,
+        dialect: CsvDialect,
+        header_end: usize,
+        threads: usize,
+        chunk_size: usize,
+    ) -> Workers {
+        let mut chunks = vec![];
+        let mut start = header_end;
+        while start < mmap.len() {
+            let end = match mmap[(start + chunk_size).min(mmap.len())..]
+                .iter()
+                .position(|&b| b == b'\n')
+            {
+                Some(idx) => start + chunk_size + idx + 1,
+                None => mmap.len(),
+            };
+            chunks.push((start, end));
+            start = end;
+        }
+
+        let mmap = Arc::new(mmap);
+        let dialect = Arc::new(dialect);
+        let (jobs, job_rx) = channel::<usize>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (result_tx, results) = channel();
+        let handles = (0..threads)
+            .map(|_| {
+                let (mmap, dialect, chunks, job_rx, result_tx) = (
+                    mmap.clone(),
+                    dialect.clone(),
+                    chunks.clone(),
+                    job_rx.clone(),
+                    result_tx.clone(),
+                );
+                std::thread::spawn(move || loop {
+                    let job = match job_rx.lock() {
+                        Ok(job_rx) => job_rx.recv(),
+                        Err(_) => return,
+                    };
+                    let idx = match job {
+                        Ok(idx) => idx,
+                        Err(_) => return,
+                    };
+                    let (start, end) = chunks[idx];
+                    // lines before the chunk are not known yet, chunks with errors are parsed
+                    // again once they are, for the errors to report the right positions
+                    let mut pos = csv::Position::new();
+                    pos.set_byte(start as u64).set_line(1).set_record(1);
+                    let chunk = catch_unwind(AssertUnwindSafe(|| {
+                        parse_chunk(&mmap, start, end, pos, &dialect)
+                    }))
+                    .unwrap_or(Err(HelaError::ParallelReadError));
+                    if result_tx.send((idx, chunk)).is_err() {
+                        return;
+                    }
+                })
+            })
+            .collect();
+
+        let mut pos = csv::Position::new();
+        pos.set_byte(header_end as u64)
+            .set_line(1 + mmap[..header_end].iter().filter(|&&b| b == b'\n').count() as u64)
+            .set_record(1);
+        let mut workers = Workers {
+            mmap,
+            dialect,
+            chunks,
+            jobs: Some(jobs),
+            results,
+            handles,
+            reorder: HashMap::new(),
+            next: 0,
+            queued: 0,
+            pos,
+            current: vec![].into_iter(),
+        };
+        // keep a few chunks per thread in flight, bounding the reorder buffer
+        for _ in 0..threads * 2 {
+            workers.queue();
+        }
+        workers
+    }
+
+    fn queue(&mut self) {
+        if self.queued < self.chunks.len() {
+            if let Some(jobs) = &self.jobs {
+                // a closed channel means a thread panicked, which the results will show
+                let _ = jobs.send(self.queued);
+            }
+            self.queued += 1;
+        }
+    }
+
+    /// Move on to the transactions of the next chunk in file order
+    fn next_chunk(&mut self) -> Option<Fallible<()>> {
+        if self.next >= self.chunks.len() {
+            return None;
+        }
+        let chunk = loop {
+            if let Some(chunk) = self.reorder.remove(&self.next) {
+                break chunk;
+            }
+            match self.results.recv() {
+                Ok((idx, chunk)) => {
+                    self.reorder.insert(idx, chunk);
+                }
+                Err(_) => return Some(Err(HelaError::ParallelReadError)),
+            }
+        };
+        let (start, end) = self.chunks[self.next];
+        self.next += 1;
+        self.queue();
+        let mut chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => return Some(Err(e)),
+        };
+        if chunk.failed {
+            chunk = match parse_chunk(&self.mmap, start, end, self.pos.clone(), &self.dialect) {
+                Ok(chunk) => chunk,
+                Err(e) => return Some(Err(e)),
+            };
+        }
+        let (line, record) = (self.pos.line(), self.pos.record());
+        self.pos
+            .set_byte(end as u64)
+            .set_line(line + chunk.lines)
+            .set_record(record + chunk.txns.len() as u64);
+        self.current = chunk.txns.into_iter();
+        Some(Ok(()))
+    }
+}
+
+impl Drop for Workers {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+enum Source {
+    Parallel(Box<Workers>),
+    Sequential(Box<CsvDataReader>),
+}
+
+/// CSV Data Reader that parses a memory-mapped file on several threads.
+///
+/// The file is split into chunks at line boundaries that are parsed in parallel
+/// and yielded in file order. Files containing quotes, whose fields may span lines,
+/// are read sequentially.
+pub struct ParallelCsvReader {
+    source: Source,
+}
+
+impl ParallelCsvReader {
+    /// Create new reader from a path, parsing on `threads` threads
+    pub fn new(fname: &str, threads: usize) -> Fallible<ParallelCsvReader> {
+        Self::with_dialect(fname, threads, &CsvDialect::default())
+    }
+
+    /// Create new reader from a path, of a given dialect
+    pub fn with_dialect(
+        fname: &str,
+        threads: usize,
+        dialect: &CsvDialect,
+    ) -> Fallible<ParallelCsvReader> {
+        Self::open(fname, threads, CHUNK_SIZE, dialect)
+    }
+
+    /// Create new reader from a path, splitting the file into chunks of about `chunk_size` bytes
+    pub fn with_chunk_size(
+        fname: &str,
+        threads: usize,
+        chunk_size: usize,
+    ) -> Fallible<ParallelCsvReader> {
+        Self::open(fname, threads, chunk_size, &CsvDialect::default())
+    }
+
+    fn open(
+        fname: &str,
+        threads: usize,
+        chunk_size: usize,
+        dialect: &CsvDialect,
+    ) -> Fallible<ParallelCsvReader> {
+        let file = File::open(fname)?;
+        if threads <= 1 || file.metadata()?.len() == 0 {
+            return Self::sequential(fname, dialect);
+        }
+        // Safety: the file must not be modified while it is read, as with any input file
+        let mmap = unsafe { Mmap::map(&file)? };
+        if matches!(dialect.quote_byte(), Some(quote) if mmap.contains(&quote)) {
+            return Self::sequential(fname, dialect);
+        }
+        dialect.validate()?;
+        let mut rdr = CsvDataReader::from_reader_with_dialect(&mmap[..], dialect);
+        let header_end = rdr.header_end()? as usize;
+        Ok(ParallelCsvReader {
+            source: Source::Parallel(Box::new(Workers::start(
+                mmap,
+                dialect.clone(),
+                header_end,
+                threads,
+                chunk_size.max(1),
+            ))),
+        })
+    }
+
+    fn sequential(fname: &str, dialect: &CsvDialect) -> Fallible<ParallelCsvReader> {
+        Ok(ParallelCsvReader {
+            source: Source::Sequential(Box::new(CsvDataReader::with_dialect(fname, dialect)?)),
+        })
+    }
+}
+
+impl Iterator for ParallelCsvReader {
+    type Item = Fallible<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.source {
+            Source::Sequential(rdr) => rdr.next(),
+            Source::Parallel(workers) => loop {
+                if let Some(txn) = workers.current.next() {
+                    return Some(txn);
+                }
+                if let Err(e) = workers.next_chunk()? {
+                    return Some(Err(e));
+                }
+            },
+        }
+    }
+}
+
+impl TransactionSource for ParallelCsvReader {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CsvWriterStdout, Workload, WorkloadGenerator};
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn matches_sequential_reader() -> Fallible<()> {
+        let workload = Workload {
+            rows: 2_000,
+            ..Default::default()
+        };
+        let mut input_file = NamedTempFile::new()?;
+        CsvWriterStdout::write_transactions(
+            WorkloadGenerator::new(workload),
+            Some(input_file.as_file_mut()),
+        )?;
+        input_file.write_all(b"\ndeposit,1,2001,abc\nwithdrawal,2,2002,1.0\n")?;
+        let fname = input_file.path().to_str().unwrap();
+
+        let errors = |txns: Vec<Fallible<Transaction>>| -> Vec<Result<Transaction, String>> {
+            txns.into_iter()
+                .map(|txn| txn.map_err(|e| e.to_string()))
+                .collect()
+        };
+        let expected = errors(CsvDataReader::new(fname)?.collect());
+        let parallel = errors(ParallelCsvReader::with_chunk_size(fname, 4, 100)?.collect());
+        assert_eq!(parallel.len(), 2_002);
+        assert_eq!(parallel, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+//! Memory-bounded retention of disputable transactions.
+use std::collections::{HashMap, VecDeque};
+use std::mem::size_of;
+
+use crate::{
+    Amount, Client, Timestamp, Transaction, TransactionId, TransactionType, AMOUNT_EPSILON,
+};
+
+/// Which transactions are retained for later disputes, and for how long
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RetentionPolicy {
+    /// Retain withdrawals, making them disputable, as well as deposits
+    pub withdrawals: bool,
+    /// Evict undisputed transactions older than this many seconds
+    pub max_age: Option<Timestamp>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            withdrawals: true,
+            max_age: None,
+        }
+    }
+}
+
+const WITHDRAWAL: u8 = 0b01;
+const HAS_AMOUNT: u8 = 0b10;
+const NO_TIMESTAMP: Timestamp = Timestamp::MAX;
+
+/// Compact form of a retained transaction
+#[derive(Copy, Clone, Debug)]
+struct RetainedTransaction {
+    amount: Amount,
+    disputed: Amount,
+    charged_back: Amount,
+    refunded: Amount,
+    timestamp: Timestamp,
+    disputed_at: Timestamp,
+    client: Client,
+    flags: u8,
+}
+
+impl RetainedTransaction {
+    fn pack(txn: &Transaction) -> RetainedTransaction {
+        let mut flags = 0;
+        if txn.r#type == TransactionType::Withdrawal {
+            flags |= WITHDRAWAL;
+        }
+        if txn.amount.is_some() {
+            flags |= HAS_AMOUNT;
+        }
+        RetainedTransaction {
+            amount: txn.amount.unwrap_or_default(),
+            disputed: txn.disputed,
+            charged_back: txn.charged_back,
+            refunded: txn.refunded,
+            timestamp: txn.timestamp.unwrap_or(NO_TIMESTAMP),
+            disputed_at: txn.disputed_at.unwrap_or(NO_TIMESTAMP),
+            client: txn.client,
+            flags,
+        }
+    }
+
+    fn unpack(&self, id: TransactionId) -> Transaction {
+        let timestamp = |ts| if ts == NO_TIMESTAMP { None } else { Some(ts) };
+        Transaction {
+            r#type: if self.flags & WITHDRAWAL != 0 {
+                TransactionType::Withdrawal
+            } else {
+                TransactionType::Deposit
+            },
+            client: self.client,
+            id,
+            amount: if self.flags & HAS_AMOUNT != 0 {
+                Some(self.amount)
+            } else {
+                None
+            },
+            timestamp: timestamp(self.timestamp),
+            destination: None,
+            disputed: self.disputed,
+            disputed_at: timestamp(self.disputed_at),
+            charged_back: self.charged_back,
+            refunded: self.refunded,
+        }
+    }
+}
+
+/// Changes made by the current unit of work, to undo on rollback
+#[derive(Clone, Debug, Default)]
+struct RetentionJournal {
+    entries: HashMap<TransactionId, Option<RetainedTransaction>>,
+    /// Clients whose queue had an entry pushed, at the front or the back, in order
+    queued: Vec<(Client, bool)>,
+    /// Latest timestamps of the clients before they changed
+    latest: HashMap<Client, Option<Timestamp>>,
+}
+
+/// Disputable transactions retained in a compact form
+#[derive(Clone, Debug, Default)]
+pub struct TransactionRetention {
+    policy: RetentionPolicy,
+    entries: HashMap<TransactionId, RetainedTransaction>,
+    /// Timestamps and ids of each client's retained transactions, roughly oldest first
+    by_age: HashMap<Client, VecDeque<(Timestamp, TransactionId)>>,
+    /// Latest timestamp of each client's retained transactions
+    latest: HashMap<Client, Timestamp>,
+    journal: Option<RetentionJournal>,
+}
+
+impl TransactionRetention {
+    /// create an empty retention store following `policy`
+    pub fn new(policy: RetentionPolicy) -> TransactionRetention {
+        TransactionRetention {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Whether the policy retains transactions of this type
+    pub fn is_retained(&self, r#type: TransactionType) -> bool {
+        match r#type {
+            TransactionType::Deposit => true,
+            TransactionType::Withdrawal => self.policy.withdrawals,
+            _ => false,
+        }
+    }
+
+    /// Retain a transaction if it is disputable, evicting expired ones
+    pub fn insert(&mut self, txn: Transaction) {
+        if !self.is_retained(txn.r#type) {
+            return;
+        }
+        self.journal_entry(txn.id);
+        self.entries.insert(txn.id, RetainedTransaction::pack(&txn));
+        // transactions are only queued by age when they can expire
+        if let (Some(timestamp), Some(_)) = (txn.timestamp, self.policy.max_age) {
+            let client = txn.client;
+            self.by_age
+                .entry(client)
+                .or_default()
+                .push_back((timestamp, txn.id));
+            let latest = self.latest.get(&client).copied();
+            self.latest
+                .insert(client, latest.unwrap_or_default().max(timestamp));
+            match self.journal.as_mut() {
+                Some(journal) => {
+                    journal.queued.push((client, false));
+                    journal.latest.entry(client).or_insert(latest);
+                }
+                None => self.evict(client),
+            }
+        }
+    }
+
+    /// Start a unit of work, deferring eviction until it is committed
+    pub fn begin(&mut self) {
+        self.journal = Some(RetentionJournal::default());
+    }
+
+    /// Keep the changes made since `begin`
+    pub fn commit(&mut self) {
+        if let Some(journal) = self.journal.take() {
+            for (client, _) in journal.queued {
+                self.evict(client);
+            }
+        }
+    }
+
+    /// Undo the changes made since `begin`
+    pub fn rollback(&mut self) {
+        let journal = match self.journal.take() {
+            Some(journal) => journal,
+            None => return,
+        };
+        for (id, entry) in journal.entries {
+            match entry {
+                Some(entry) => self.entries.insert(id, entry),
+                None => self.entries.remove(&id),
+            };
+        }
+        for (client, front) in journal.queued.into_iter().rev() {
+            if let Some(queue) = self.by_age.get_mut(&client) {
+                if front {
+                    queue.pop_front();
+                } else {
+                    queue.pop_back();
+                }
+            }
+        }
+        for (client, latest) in journal.latest {
+            match latest {
+                Some(latest) => self.latest.insert(client, latest),
+                None => self.latest.remove(&client),
+            };
+        }
+    }
+
+    /// Remember an entry as it was before the current unit of work changes it
+    fn journal_entry(&mut self, id: TransactionId) {
+        if let Some(journal) = self.journal.as_mut() {
+            let entries = &self.entries;
+            journal
+                .entries
+                .entry(id)
+                .or_insert_with(|| entries.get(&id).copied());
+        }
+    }
+
+    fn entry_mut(&mut self, id: TransactionId) -> Option<&mut RetainedTransaction> {
+        self.journal_entry(id);
+        self.entries.get_mut(&id)
+    }
+
+    /// Fetch a retained transaction
+    pub fn get(&self, id: TransactionId) -> Option<Transaction> {
+        self.entries.get(&id).map(|entry| entry.unpack(id))
+    }
+
+    /// Add to the disputed amount of a retained transaction,
+    /// returning false if it is not retained
+    pub fn add_disputed(
+        &mut self,
+        id: TransactionId,
+        amount: Amount,
+        at: Option<Timestamp>,
+    ) -> bool {
+        match self.entry_mut(id) {
+            Some(entry) => {
+                if entry.disputed <= 0.0 {
+                    entry.disputed_at = at.unwrap_or(NO_TIMESTAMP);
+                }
+                entry.disputed += amount;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove from the disputed amount of a retained transaction, either resolving
+    /// or charging it back, returning false if it is not retained
+    pub fn remove_disputed(
+        &mut self,
+        id: TransactionId,
+        amount: Amount,
+        charged_back: bool,
+    ) -> bool {
+        let entry = match self.entry_mut(id) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        entry.disputed -= amount;
+        if charged_back {
+            entry.charged_back += amount;
+        }
+        if entry.disputed <= AMOUNT_EPSILON {
+            entry.disputed = 0.0;
+            entry.disputed_at = NO_TIMESTAMP;
+            // disputed transactions are skipped by eviction, queue it again,
+            // at the front as it is most likely older than everything queued
+            let (timestamp, client) = (entry.timestamp, entry.client);
+            if timestamp != NO_TIMESTAMP && self.policy.max_age.is_some() {
+                self.by_age
+                    .entry(client)
+                    .or_default()
+                    .push_front((timestamp, id));
+                if let Some(journal) = self.journal.as_mut() {
+                    journal.queued.push((client, true));
+                }
+            }
+        }
+        true
+    }
+
+    /// Add to the refunded amount of a retained transaction,
+    /// returning false if it is not retained
+    pub fn add_refunded(&mut self, id: TransactionId, amount: Amount) -> bool {
+        match self.entry_mut(id) {
+            Some(entry) => {
+                entry.refunded += amount;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Number of retained transactions
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no transactions are retained
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Estimated heap memory used, in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.entries.capacity() * size_of::<(TransactionId, RetainedTransaction)>()
+            + self.by_age.capacity() * size_of::<(Client, VecDeque<(Timestamp, TransactionId)>)>()
+            + self
+                .by_age
+                .values()
+                .map(|queue| queue.capacity() * size_of::<(Timestamp, TransactionId)>())
+                .sum::<usize>()
+            + self.latest.capacity() * size_of::<(Client, Timestamp)>()
+    }
+
+    /// Evict a client's undisputed transactions older than the policy's maximum age.
+    ///
+    /// Timestamps only increase per client, so age is measured against the client's own
+    /// latest timestamp, as its later disputes cannot be any earlier. Transactions queued
+    /// again after their dispute ended may be out of order, which only evicts the ones
+    /// queued behind them late.
+    fn evict(&mut self, client: Client) {
+        let max_age = match self.policy.max_age {
+            Some(max_age) => max_age,
+            None => return,
+        };
+        let latest = self.latest.get(&client).copied().unwrap_or_default();
+        let queue = match self.by_age.get_mut(&client) {
+            Some(queue) => queue,
+            None => return,
+        };
+        while let Some(&(timestamp, id)) = queue.front() {
+            if latest.saturating_sub(timestamp) <= max_age {
+                break;
+            }
+            queue.pop_front();
+            if let Some(entry) = self.entries.get(&id) {
+                if entry.timestamp == timestamp && entry.disputed <= 0.0 {
+                    self.entries.remove(&id);
+                }
+            }
+        }
+        if queue.is_empty() {
+            self.by_age.remove(&client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txn(r#type: TransactionType, id: TransactionId, timestamp: Timestamp) -> Transaction {
+        Transaction {
+            r#type,
+            client: 1,
+            id,
+            amount: Some(1.5),
+            timestamp: Some(timestamp),
+            destination: None,
+            disputed: 0.0,
+            disputed_at: None,
+            charged_back: 0.0,
+            refunded: 0.0,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut retention = TransactionRetention::new(RetentionPolicy::default());
+        let deposit = txn(TransactionType::Deposit, 1, 10);
+        retention.insert(deposit);
+        assert_eq!(retention.get(1), Some(deposit));
+        assert!(retention.add_disputed(1, 1.0, Some(20)));
+        assert!(retention.add_disputed(1, 0.5, Some(30)));
+        assert!(retention.remove_disputed(1, 0.5, true));
+        let disputed = retention.get(1).unwrap();
+        assert_eq!(disputed.disputed, 1.0);
+        assert_eq!(disputed.charged_back, 0.5);
+        assert_eq!(disputed.disputed_at, Some(20));
+    }
+
+    #[test]
+    fn skips_withdrawals() {
+        let mut retention = TransactionRetention::new(RetentionPolicy {
+            withdrawals: false,
+            max_age: None,
+        });
+        retention.insert(txn(TransactionType::Withdrawal, 1, 10));
+        assert!(retention.is_empty());
+        retention.insert(txn(TransactionType::Deposit, 2, 10));
+        assert!(retention.by_age.is_empty());
+        assert!(retention.latest.is_empty());
+    }
+
+    #[test]
+    fn evicts_expired() {
+        let mut retention = TransactionRetention::new(RetentionPolicy {
+            withdrawals: true,
+            max_age: Some(100),
+        });
+        retention.insert(txn(TransactionType::Deposit, 1, 0));
+        retention.insert(txn(TransactionType::Deposit, 2, 50));
+        retention.add_disputed(2, 1.5, Some(60));
+        retention.insert(txn(TransactionType::Deposit, 3, 200));
+        assert_eq!(retention.get(1), None);
+        assert!(retention.get(2).is_some());
+        retention.remove_disputed(2, 1.5, false);
+        retention.insert(txn(TransactionType::Deposit, 4, 201));
+        assert_eq!(retention.get(2), None);
+        assert_eq!(retention.len(), 2);
+
+        // another client's later transactions do not age this client's ones
+        let other = Transaction {
+            client: 2,
+            ..txn(TransactionType::Deposit, 5, 1000)
+        };
+        retention.insert(other);
+        assert!(retention.get(3).is_some());
+        assert!(retention.get(4).is_some());
+    }
+}
@@ -0,0 +1,183 @@
+//! Aggregate statistics of a run.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{Account, Amount};
+
+/// Why a transaction was ignored without changing any account
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum IgnoreReason {
+    /// Withdrawal of more than the available funds
+    InsufficientFunds,
+    /// Dispute, resolve or chargeback of a transaction that is not known
+    UnknownTransaction,
+    /// Resolve or chargeback of a transaction that is not under dispute
+    NotDisputed,
+}
+
+impl IgnoreReason {
+    /// Reason name, as used in the statistics
+    pub fn name(&self) -> &'static str {
+        match self {
+            IgnoreReason::InsufficientFunds => "insufficient_funds",
+            IgnoreReason::UnknownTransaction => "unknown_transaction",
+            IgnoreReason::NotDisputed => "not_disputed",
+        }
+    }
+}
+
+/// Statistics of the transactions executed by an engine
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RunStats {
+    /// Number of transactions executed, by type
+    pub transactions: BTreeMap<&'static str, u64>,
+    /// Number of transactions ignored, by reason
+    pub ignored: BTreeMap<&'static str, u64>,
+    /// Number of transactions rejected, by reason
+    pub rejected: BTreeMap<&'static str, u64>,
+    /// Sum of the deposits applied
+    pub deposited: Amount,
+    /// Sum of the withdrawals applied
+    pub withdrawn: Amount,
+    /// Sum of the chargebacks applied
+    pub charged_back: Amount,
+    /// Sum of the amounts held in the accounts
+    pub held: Amount,
+    /// Number of locked accounts
+    pub locked_accounts: usize,
+    /// Seconds spent executing the transactions, if measured
+    pub elapsed_secs: Option<f64>,
+    /// Transactions executed per second, if measured
+    pub throughput: Option<f64>,
+}
+
+impl RunStats {
+    /// Total number of transactions executed
+    pub fn total(&self) -> u64 {
+        self.transactions.values().sum()
+    }
+
+    /// Add the held amounts and locked status of the final accounts
+    pub fn with_accounts(mut self, accounts: impl Iterator<Item = Account>) -> RunStats {
+        for acc in accounts {
+            self.held += acc.held;
+            self.locked_accounts += acc.locked as usize;
+        }
+        self
+    }
+
+    /// Add the time spent executing the transactions
+    pub fn with_elapsed(mut self, elapsed: Duration) -> RunStats {
+        let secs = elapsed.as_secs_f64();
+        self.elapsed_secs = Some(secs);
+        self.throughput = Some(self.total() as f64 / secs.max(f64::EPSILON));
+        self
+    }
+}
+
+/// Counts and their sum, e.g. `3 (deposit 2, withdrawal 1)`
+fn counts(f: &mut fmt::Formatter, counts: &BTreeMap<&'static str, u64>) -> fmt::Result {
+    write!(f, "{}", counts.values().sum::<u64>())?;
+    for (idx, (name, count)) in counts.iter().enumerate() {
+        let sep = if idx == 0 { " (" } else { ", " };
+        write!(f, "{}{} {}", sep, name, count)?;
+    }
+    if !counts.is_empty() {
+        write!(f, ")")?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for RunStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "transactions: ")?;
+        counts(f, &self.transactions)?;
+        write!(f, "\nignored: ")?;
+        counts(f, &self.ignored)?;
+        write!(f, "\nrejected: ")?;
+        counts(f, &self.rejected)?;
+        write!(
+            f,
+            "\ndeposited: {:.2}, withdrawn: {:.2}, charged back: {:.2}, held: {:.2}\nlocked accounts: {}",
+            self.deposited, self.withdrawn, self.charged_back, self.held, self.locked_accounts
+        )?;
+        if let (Some(secs), Some(throughput)) = (self.elapsed_secs, self.throughput) {
+            write!(
+                f,
+                "\nelapsed: {:.3}s, {:.0} transactions/s",
+                secs, throughput
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats() -> RunStats {
+        let mut stats = RunStats {
+            deposited: 10.0,
+            withdrawn: 2.5,
+            ..Default::default()
+        };
+        stats.transactions.insert("deposit", 3);
+        stats.transactions.insert("withdrawal", 1);
+        stats.ignored.insert(IgnoreReason::NotDisputed.name(), 1);
+        stats.with_accounts(
+            vec![
+                Account {
+                    client: 1,
+                    held: 1.5,
+                    locked: true,
+                    ..Default::default()
+                },
+                Account {
+                    client: 2,
+                    held: 0.5,
+                    ..Default::default()
+                },
+            ]
+            .into_iter(),
+        )
+    }
+
+    #[test]
+    fn displays_stats() {
+        let stats = stats().with_elapsed(Duration::from_millis(500));
+        assert_eq!(stats.total(), 4);
+        assert_eq!(stats.elapsed_secs, Some(0.5));
+        assert_eq!(stats.throughput, Some(8.0));
+        assert_eq!(
+            stats.to_string(),
+            "transactions: 4 (deposit 3, withdrawal 1)\nignored: 1 (not_disputed 1)\nrejected: 0\n\
+             deposited: 10.00, withdrawn: 2.50, charged back: 0.00, held: 2.00\nlocked accounts: 1\n\
+             elapsed: 0.500s, 8 transactions/s"
+        );
+        assert!(!RunStats::default().to_string().contains("elapsed"));
+    }
+
+    #[test]
+    fn serializes_stats() {
+        let json = serde_json::to_value(stats()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "transactions": {"deposit": 3, "withdrawal": 1},
+                "ignored": {"not_disputed": 1},
+                "rejected": {},
+                "deposited": 10.0,
+                "withdrawn": 2.5,
+                "charged_back": 0.0,
+                "held": 2.0,
+                "locked_accounts": 1,
+                "elapsed_secs": null,
+                "throughput": null,
+            })
+        );
+    }
+}
@@ -1,40 +1,81 @@
 use std::collections::HashMap;
+use std::mem::size_of;
+
+use tracing::{debug, trace};
 
 use crate::{
-    Account, AccountStore, Amount, Client, Fallible, HelaError, Storage, Transaction,
-    TransactionId, TransactionStore,
+    Account, AccountStore, Amount, Client, Fallible, HelaError, MemoryUsage, RetentionPolicy,
+    Storage, Timestamp, Transaction, TransactionId, TransactionRetention, TransactionStore,
 };
 
 /// In Memory data store for Accounts and Transaction
+///
+/// Only disputable transactions are stored, as decided by the store's [`RetentionPolicy`].
 #[derive(Debug, Clone)]
 pub struct InMemoryStore {
     accounts: HashMap<Client, Account>,
-    transactions: HashMap<TransactionId, Transaction>,
+    /// Credit limits of clients without an account yet, applied when it is created
+    credit_limits: HashMap<Client, Amount>,
+    transactions: TransactionRetention,
+    /// Accounts as they were before the current unit of work changed them
+    journal: Option<HashMap<Client, Option<Account>>>,
+    /// Credit limits of clients without an account as they were before the current unit of work
+    limits_journal: HashMap<Client, Option<Amount>>,
 }
 
 impl InMemoryStore {
     /// create an empty store
     pub fn new() -> InMemoryStore {
+        Self::with_retention(RetentionPolicy::default())
+    }
+
+    /// create an empty store retaining transactions according to `policy`
+    pub fn with_retention(policy: RetentionPolicy) -> InMemoryStore {
         InMemoryStore {
             accounts: HashMap::new(),
-            transactions: HashMap::new(),
+            credit_limits: HashMap::new(),
+            transactions: TransactionRetention::new(policy),
+            journal: None,
+            limits_journal: HashMap::new(),
+        }
+    }
+
+    /// Remember an account as it was before the current unit of work changes it
+    fn journal_account(&mut self, client_id: Client) {
+        if let Some(journal) = self.journal.as_mut() {
+            let accounts = &self.accounts;
+            journal
+                .entry(client_id)
+                .or_insert_with(|| accounts.get(&client_id).copied());
         }
     }
 
     fn get_account_mut(&mut self, client_id: Client) -> &mut Account {
-        self.accounts.entry(client_id).or_insert(Account {
+        self.journal_account(client_id);
+        let credit_limits = &self.credit_limits;
+        self.accounts.entry(client_id).or_insert_with(|| Account {
             client: client_id,
+            credit_limit: credit_limits.get(&client_id).copied().unwrap_or_default(),
             ..Default::default()
         })
     }
 }
 
 impl AccountStore for InMemoryStore {
+    fn adjust(&mut self, client_id: Client, amount: Amount) -> Fallible<()> {
+        trace!(client = client_id, amount, "adjust");
+        let acc = self.get_account_mut(client_id);
+        acc.available += amount;
+        acc.total += amount;
+        Ok(())
+    }
+
     fn is_locked(&self, client_id: Client) -> Fallible<bool> {
         self.get_account(client_id).map(|acc| acc.locked)
     }
 
     fn chargeback(&mut self, client_id: Client, amount: Amount) -> Fallible<()> {
+        trace!(client = client_id, amount, "chargeback, locking account");
         let acc = self.get_account_mut(client_id);
         acc.held -= amount;
         acc.total -= amount;
@@ -46,6 +87,7 @@ impl AccountStore for InMemoryStore {
     }
 
     fn deposit(&mut self, client_id: Client, amount: Amount) -> Fallible<()> {
+        trace!(client = client_id, amount, "deposit");
         let acc = self.get_account_mut(client_id);
         acc.available += amount;
         acc.total += amount;
@@ -53,6 +95,7 @@ impl AccountStore for InMemoryStore {
     }
 
     fn dispute(&mut self, client_id: Client, amount: Amount) -> Fallible<()> {
+        trace!(client = client_id, amount, "hold");
         let acc = self.get_account_mut(client_id);
         acc.available -= amount;
         acc.held += amount;
@@ -71,12 +114,14 @@ impl AccountStore for InMemoryStore {
     }
 
     fn lock_account(&mut self, client_id: Client) -> Fallible<()> {
+        trace!(client = client_id, "lock account");
         let acc = self.get_account_mut(client_id);
         acc.locked = true;
         Ok(())
     }
 
     fn resolve(&mut self, client_id: Client, amount: Amount) -> Fallible<()> {
+        trace!(client = client_id, amount, "release");
         let acc = self.get_account_mut(client_id);
         acc.held -= amount;
         acc.available += amount;
@@ -86,14 +131,76 @@ impl AccountStore for InMemoryStore {
         Ok(())
     }
 
+    fn set_credit_limit(&mut self, client_id: Client, limit: Amount) -> Fallible<()> {
+        if !limit.is_finite() || limit < 0.0 {
+            return Err(HelaError::InvalidCreditLimit(client_id, limit));
+        }
+        trace!(client = client_id, limit, "set credit limit");
+        if self.accounts.contains_key(&client_id) {
+            self.get_account_mut(client_id).credit_limit = limit;
+        } else {
+            let previous = self.credit_limits.insert(client_id, limit);
+            if self.journal.is_some() {
+                self.limits_journal.entry(client_id).or_insert(previous);
+            }
+        }
+        Ok(())
+    }
+
+    fn unlock_account(&mut self, client_id: Client) -> Fallible<()> {
+        trace!(client = client_id, "unlock account");
+        self.journal_account(client_id);
+        let acc = self
+            .accounts
+            .get_mut(&client_id)
+            .ok_or(HelaError::AccountNotFound(client_id))?;
+        acc.locked = false;
+        Ok(())
+    }
+
+    fn transfer(&mut self, from: Client, to: Client, amount: Amount) -> Fallible<()> {
+        trace!(from, to, amount, "transfer");
+        for client_id in [from, to].iter() {
+            if self.accounts.get(client_id).is_some_and(|acc| acc.locked) {
+                debug!(client = client_id, "account locked");
+                return Err(HelaError::AccountLocked(*client_id));
+            }
+        }
+        let sufficient = self
+            .accounts
+            .get(&from)
+            .is_some_and(|acc| acc.available + acc.credit_limit >= amount);
+        if !sufficient {
+            debug!(client = from, amount, "insufficient funds");
+            return Err(HelaError::InsufficientAccountFunds(from));
+        }
+
+        let source = self.get_account_mut(from);
+        source.available -= amount;
+        source.total -= amount;
+        let destination = self.get_account_mut(to);
+        destination.available += amount;
+        destination.total += amount;
+        Ok(())
+    }
+
     fn store_account(&mut self, acc: Account) -> Fallible<()> {
+        trace!(client = acc.client, "store account");
+        self.journal_account(acc.client);
         self.accounts.insert(acc.client, acc);
         Ok(())
     }
 
     fn withdraw(&mut self, client_id: Client, amount: Amount) -> Fallible<()> {
+        trace!(client = client_id, amount, "withdraw");
         let acc = self.get_account_mut(client_id);
-        if acc.available < amount {
+        if acc.available + acc.credit_limit < amount {
+            debug!(
+                client = client_id,
+                amount,
+                available = acc.available,
+                "insufficient funds"
+            );
             return Err(HelaError::InsufficientAccountFunds(client_id));
         }
         acc.available -= amount;
@@ -114,11 +221,9 @@ impl AccountStore for InMemoryStore {
 
 impl TransactionStore for InMemoryStore {
     fn get_transaction(&self, id: TransactionId) -> Fallible<Transaction> {
-        let txn = *self
-            .transactions
-            .get(&id)
-            .ok_or(HelaError::TransactionNotFound(id))?;
-        Ok(txn)
+        self.transactions
+            .get(id)
+            .ok_or(HelaError::TransactionNotFound(id))
     }
 
     fn get_transaction_amount(&self, id: TransactionId) -> Fallible<Option<Amount>> {
@@ -126,30 +231,107 @@ impl TransactionStore for InMemoryStore {
     }
 
     fn store_transaction(&mut self, txn: Transaction) -> Fallible<()> {
-        self.transactions.insert(txn.id, txn);
+        trace!(tx = txn.id, "store transaction");
+        self.transactions.insert(txn);
+        Ok(())
+    }
+
+    fn mark_transaction_as_disputed(
+        &mut self,
+        id: TransactionId,
+        amount: Amount,
+        at: Option<Timestamp>,
+    ) -> Fallible<()> {
+        if self.transactions.add_disputed(id, amount, at) {
+            Ok(())
+        } else {
+            Err(HelaError::TransactionNotFound(id))
+        }
+    }
+
+    fn mark_transaction_as_undisputed(
+        &mut self,
+        id: TransactionId,
+        amount: Amount,
+    ) -> Fallible<()> {
+        if self.transactions.remove_disputed(id, amount, false) {
+            Ok(())
+        } else {
+            Err(HelaError::TransactionNotFound(id))
+        }
+    }
+
+    fn mark_transaction_as_refunded(&mut self, id: TransactionId, amount: Amount) -> Fallible<()> {
+        if self.transactions.add_refunded(id, amount) {
+            Ok(())
+        } else {
+            Err(HelaError::TransactionNotFound(id))
+        }
+    }
+
+    fn mark_transaction_as_charged_back(
+        &mut self,
+        id: TransactionId,
+        amount: Amount,
+    ) -> Fallible<()> {
+        if self.transactions.remove_disputed(id, amount, true) {
+            Ok(())
+        } else {
+            Err(HelaError::TransactionNotFound(id))
+        }
+    }
+}
+
+impl Storage for InMemoryStore {
+    fn begin(&mut self) -> Fallible<()> {
+        if self.journal.is_some() {
+            return Err(HelaError::UnitOfWorkError("unit of work already started"));
+        }
+        self.journal = Some(HashMap::new());
+        self.transactions.begin();
         Ok(())
     }
 
-    fn mark_transaction_as_disputed(&mut self, id: TransactionId) -> Fallible<()> {
-        let txn = self
-            .transactions
-            .get_mut(&id)
-            .ok_or(HelaError::TransactionNotFound(id))?;
-        txn.disputed = true;
+    fn commit(&mut self) -> Fallible<()> {
+        self.journal
+            .take()
+            .ok_or(HelaError::UnitOfWorkError("no unit of work to commit"))?;
+        self.limits_journal.clear();
+        self.transactions.commit();
         Ok(())
     }
 
-    fn mark_transaction_as_undisputed(&mut self, id: TransactionId) -> Fallible<()> {
-        let txn = self
-            .transactions
-            .get_mut(&id)
-            .ok_or(HelaError::TransactionNotFound(id))?;
-        txn.disputed = false;
+    fn rollback(&mut self) -> Fallible<()> {
+        let journal = self
+            .journal
+            .take()
+            .ok_or(HelaError::UnitOfWorkError("no unit of work to roll back"))?;
+        trace!(accounts = journal.len(), "roll back");
+        for (client_id, acc) in journal {
+            match acc {
+                Some(acc) => self.accounts.insert(client_id, acc),
+                None => self.accounts.remove(&client_id),
+            };
+        }
+        for (client_id, limit) in self.limits_journal.drain() {
+            match limit {
+                Some(limit) => self.credit_limits.insert(client_id, limit),
+                None => self.credit_limits.remove(&client_id),
+            };
+        }
+        self.transactions.rollback();
         Ok(())
     }
-}
 
-impl Storage for InMemoryStore {}
+    fn memory_usage(&self) -> Option<MemoryUsage> {
+        Some(MemoryUsage {
+            accounts: self.accounts.len(),
+            transactions: self.transactions.len(),
+            bytes: self.accounts.capacity() * size_of::<(Client, Account)>()
+                + self.transactions.memory_usage(),
+        })
+    }
+}
 
 impl Default for InMemoryStore {
     fn default() -> Self {
@@ -159,6 +341,18 @@ impl Default for InMemoryStore {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::TransactionType;
+
+    mod conformance {
+        use crate::InMemoryStore;
+
+        crate::storage_conformance_tests!(InMemoryStore::new());
+
+        #[test]
+        fn run_all() -> crate::Fallible<()> {
+            crate::conformance::run_all(InMemoryStore::new)
+        }
+    }
 
     #[test]
     fn store_and_fetch() -> Fallible<()> {
@@ -169,11 +363,139 @@ mod tests {
             held: 0f64,
             total: 100f64,
             locked: false,
+            credit_limit: 0f64,
         };
         acc.check_invariants();
-        store.store_account(acc.clone())?;
+        store.store_account(acc)?;
         let racc = store.get_account(acc.client)?;
         assert_eq!(acc, racc);
         Ok(())
     }
+
+    #[test]
+    fn retention_policy() -> Fallible<()> {
+        let mut store = InMemoryStore::with_retention(RetentionPolicy {
+            withdrawals: false,
+            max_age: None,
+        });
+        let deposit = Transaction {
+            r#type: TransactionType::Deposit,
+            client: 1,
+            id: 1,
+            amount: Some(2.0),
+            timestamp: None,
+            destination: None,
+            disputed: 0.0,
+            disputed_at: None,
+            charged_back: 0.0,
+            refunded: 0.0,
+        };
+        store.store_transaction(deposit)?;
+        store.store_transaction(Transaction {
+            r#type: TransactionType::Withdrawal,
+            id: 2,
+            ..deposit
+        })?;
+        assert_eq!(store.get_transaction(1)?, deposit);
+        assert!(matches!(
+            store.get_transaction(2),
+            Err(HelaError::TransactionNotFound(2))
+        ));
+        assert_eq!(
+            store.memory_usage().map(|usage| usage.transactions),
+            Some(1)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn transfer_is_atomic() -> Fallible<()> {
+        let mut store = InMemoryStore::new();
+        store.deposit(1, 10f64)?;
+        store.deposit(2, 1f64)?;
+        store.transfer(1, 2, 4f64)?;
+        assert_eq!(store.get_account(1)?.total, 6f64);
+        assert_eq!(store.get_account(2)?.total, 5f64);
+        assert!(matches!(
+            store.transfer(2, 1, 6f64),
+            Err(HelaError::InsufficientAccountFunds(2))
+        ));
+        store.lock_account(2)?;
+        assert!(matches!(
+            store.transfer(1, 2, 1f64),
+            Err(HelaError::AccountLocked(2))
+        ));
+        assert_eq!(store.get_account(1)?.total, 6f64);
+        assert_eq!(store.get_account(2)?.total, 5f64);
+        assert!(matches!(
+            store.transfer(3, 1, 1f64),
+            Err(HelaError::InsufficientAccountFunds(3))
+        ));
+        assert!(store.get_account(3).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn rollback_unit_of_work() -> Fallible<()> {
+        let mut store = InMemoryStore::new();
+        let deposit = Transaction {
+            r#type: TransactionType::Deposit,
+            client: 1,
+            id: 1,
+            amount: Some(2.0),
+            timestamp: None,
+            destination: None,
+            disputed: 0.0,
+            disputed_at: None,
+            charged_back: 0.0,
+            refunded: 0.0,
+        };
+        store.deposit(1, 2.0)?;
+        store.store_transaction(deposit)?;
+
+        store.begin()?;
+        store.dispute(1, 2.0)?;
+        store.mark_transaction_as_disputed(1, 2.0, None)?;
+        store.deposit(2, 5.0)?;
+        store.store_transaction(Transaction {
+            client: 2,
+            id: 2,
+            ..deposit
+        })?;
+        store.rollback()?;
+
+        assert_eq!(store.get_account(1)?.available, 2.0);
+        assert_eq!(store.get_transaction(1)?, deposit);
+        assert!(store.get_account(2).is_err());
+        assert!(store.get_transaction(2).is_err());
+
+        store.begin()?;
+        store.deposit(2, 5.0)?;
+        store.commit()?;
+        assert_eq!(store.get_account(2)?.available, 5.0);
+        assert!(store.commit().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn withdraw_within_credit_limit() -> Fallible<()> {
+        let mut store = InMemoryStore::new();
+        store.deposit(1, 10f64)?;
+        store.set_credit_limit(1, 5f64)?;
+        store.withdraw(1, 15f64)?;
+        let acc = store.get_account(1)?;
+        assert_eq!(acc.available, -5f64);
+        assert_eq!(acc.total, -5f64);
+        assert!(matches!(
+            store.withdraw(1, 0.01),
+            Err(HelaError::InsufficientAccountFunds(1))
+        ));
+
+        // a limit set before the first transaction applies once the account is created
+        store.set_credit_limit(2, 3f64)?;
+        assert!(store.get_account(2).is_err());
+        store.withdraw(2, 3f64)?;
+        assert_eq!(store.get_account(2)?.available, -3f64);
+        Ok(())
+    }
 }